@@ -5,10 +5,10 @@ set -e
 export RUSTFLAGS=
 export CARGO_INCREMENTAL=0
 
-cargo build --target wasm32-unknown-unknown --release
+cargo build --target wasm32-unknown-unknown --release --lib --no-default-features
 wasm-gc target/wasm32-unknown-unknown/release/wad_render.wasm -o wad_render.gc.wasm
 # cp target/wasm32-unknown-unknown/release/wad_render.wasm wad_render.gc.wasm
 
-# cargo build --target wasm32-unknown-unknown
+# cargo build --target wasm32-unknown-unknown --lib --no-default-features
 # wasm-gc target/wasm32-unknown-unknown/debug/wad_render.wasm -o wad_render.gc.wasm
 # cp target/wasm32-unknown-unknown/debug/wad_render.wasm wad_render.gc.wasm
@@ -56,6 +56,9 @@ function length(v) {
 function initCamera(cameraDom, initialState, callback) {
     const arrowSize = 64;
 
+    // Length of the lines showing the edges of the field of view
+    const fovLineLength = 256;
+
     const focusPoint = {
         x: initialState.focusPoint.x,
         y: initialState.focusPoint.y,
@@ -66,6 +69,8 @@ function initCamera(cameraDom, initialState, callback) {
         y: initialState.direction.y,
     };
 
+    let fov = initialState.fov;
+
     const targetPoint = {
         x: focusPoint.x + direction.x * 256,
         y: focusPoint.y + direction.y * 256,
@@ -101,11 +106,8 @@ function initCamera(cameraDom, initialState, callback) {
         dom.direction.setAttribute("x2", focusPoint.x + offset.x);
         dom.direction.setAttribute("y2", focusPoint.y + offset.y);
 
-        const TAU = Math.PI * 2;
-        const projection_plane_width = 320.;
-        const fov = 90. * TAU / 360.;
-        const projection_plane_half_width = projection_plane_width / 2.;
-        const distance_to_projection_plane = projection_plane_half_width / Math.tan(fov / 2.);
+        const forward = Math.cos(fov / 2) * fovLineLength;
+        const sideways = Math.sin(fov / 2) * fovLineLength;
 
         const side = {
             x: -direction.y,
@@ -114,13 +116,13 @@ function initCamera(cameraDom, initialState, callback) {
 
         dom.fovLeft.setAttribute("x1", focusPoint.x);
         dom.fovLeft.setAttribute("y1", focusPoint.y);
-        dom.fovLeft.setAttribute("x2", focusPoint.x + direction.x * distance_to_projection_plane - side.x * projection_plane_half_width);
-        dom.fovLeft.setAttribute("y2", focusPoint.y + direction.y * distance_to_projection_plane - side.y * projection_plane_half_width);
+        dom.fovLeft.setAttribute("x2", focusPoint.x + direction.x * forward - side.x * sideways);
+        dom.fovLeft.setAttribute("y2", focusPoint.y + direction.y * forward - side.y * sideways);
 
         dom.fovRight.setAttribute("x1", focusPoint.x);
         dom.fovRight.setAttribute("y1", focusPoint.y);
-        dom.fovRight.setAttribute("x2", focusPoint.x + direction.x * distance_to_projection_plane + side.x * projection_plane_half_width);
-        dom.fovRight.setAttribute("y2", focusPoint.y + direction.y * distance_to_projection_plane + side.y * projection_plane_half_width);
+        dom.fovRight.setAttribute("x2", focusPoint.x + direction.x * forward + side.x * sideways);
+        dom.fovRight.setAttribute("y2", focusPoint.y + direction.y * forward + side.y * sideways);
     }
 
     draggable(cameraDom.querySelector(".camera--target"), (x, y) => {
@@ -164,7 +166,7 @@ function initCamera(cameraDom, initialState, callback) {
     }
     updateDom();
 
-    return (newFocusPoint, newDirection) => {
+    return (newFocusPoint, newDirection, newFov) => {
         const sightlineLength = Math.min(
             Math.max(
                 length({
@@ -182,6 +184,8 @@ function initCamera(cameraDom, initialState, callback) {
         direction.x = newDirection.x;
         direction.y = newDirection.y;
 
+        if (newFov !== undefined) fov = newFov;
+
         targetPoint.x = focusPoint.x + sightlineLength * direction.x;
         targetPoint.y = focusPoint.y + sightlineLength * direction.y;
 
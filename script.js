@@ -2,11 +2,18 @@
 
 import interactiveMap from './interactive-map.js';
 
-const WIDTH = 320;
-const HEIGHT = 200;
-const FRAME_BYTE_SIZE = WIDTH * HEIGHT * 4;
+const PLAYER_RADIUS = 16;
+const TICS_PER_SECOND = 35;
 
-function fpsControls(dom, pos, dir, update) {
+// Looking up and down is limited to 45 degrees, like in the renderer
+const MAX_PITCH = Math.PI / 4;
+
+// Camera modes, as understood by camera_set_mode
+const WALK = 0;
+const CROUCH = 1;
+const FLY = 2;
+
+function fpsControls(dom, pos, dir, move, camera, update) {
     dom.addEventListener("click", ev => {
         ev.preventDefault();
         ev.stopPropagation();
@@ -20,6 +27,7 @@ function fpsControls(dom, pos, dir, update) {
         const d = dir();
         const ang = -ev.movementX / 90;
 
+        camera.look(-ev.movementY / 90);
         update(
             pos(),
             {
@@ -34,8 +42,16 @@ function fpsControls(dom, pos, dir, update) {
         's': false,
         'a': false,
         'd': false,
+        'e': false,
+        'q': false,
     };
 
+    let mode = WALK;
+    function toggleMode(newMode) {
+        mode = mode === newMode ? WALK : newMode;
+        camera.setMode(mode);
+    }
+
     let animating = false;
     let prevTimer = null;
     function animate() {
@@ -48,24 +64,33 @@ function fpsControls(dom, pos, dir, update) {
     function animationFrame(timer) {
         const fwd = (held['w'] ? 1 : 0) + (held['s'] ? -1 : 0);
         const rig = (held['d'] ? 1 : 0) + (held['a'] ? -1 : 0);
-        if (fwd == 0 && rig == 0) {
-            animating = false;
-            return;
-        }
 
-        const dt = timer - prevTimer;
+        const dt = Math.max(timer - prevTimer, 0);
         const l = dt * 0.3;
 
-        const p = pos();
         const d = dir();
         const s = { x: d.y, y: -d.x };
-        update(
-            {
-                x: p.x + fwd * l * d.x + rig * l * s.x,
-                y: p.y + fwd * l * d.y + rig * l * s.y,
-            },
-            d
-        )
+        const oldPos = pos();
+        const newPos = (fwd == 0 && rig == 0) ? oldPos : move(oldPos, {
+            x: fwd * l * d.x + rig * l * s.x,
+            y: fwd * l * d.y + rig * l * s.y,
+        });
+
+        // Speed in map units per tic, for the weapon bob
+        const tics = dt * TICS_PER_SECOND / 1000;
+        const moved = Math.hypot(newPos.x - oldPos.x, newPos.y - oldPos.y);
+        camera.setSpeed(tics > 0 ? moved / tics : 0);
+
+        camera.fly((held['e'] ? 1 : 0) + (held['q'] ? -1 : 0));
+        const cameraMoving = camera.tick(newPos, dt / 1000);
+
+        update(newPos, d);
+
+        // Keep going while the camera settles after a fall or a step
+        if (fwd == 0 && rig == 0 && !cameraMoving) {
+            animating = false;
+            return;
+        }
 
         prevTimer = timer;
         requestAnimationFrame(animationFrame);
@@ -73,7 +98,14 @@ function fpsControls(dom, pos, dir, update) {
 
     function keydown(ev) {
         const k = ev.key.toLowerCase();
-        if (k != 'w' && k != 's' && k != 'a' && k != 'd') return;
+        if (k == 'c' || k == 'f') {
+            ev.preventDefault();
+            ev.stopPropagation();
+            if (!ev.repeat) toggleMode(k == 'c' ? CROUCH : FLY);
+            animate();
+            return;
+        }
+        if (!(k in held)) return;
 
         ev.preventDefault();
         ev.stopPropagation();
@@ -85,7 +117,7 @@ function fpsControls(dom, pos, dir, update) {
 
     function keyup(ev) {
         const k = ev.key.toLowerCase();
-        if (k != 'w' && k != 's' && k != 'a' && k != 'd') return;
+        if (!(k in held)) return;
 
         ev.preventDefault();
         ev.stopPropagation();
@@ -106,7 +138,7 @@ function fpsControls(dom, pos, dir, update) {
             document.removeEventListener("mousemove", mousemove, false);
             document.removeEventListener("keydown", keydown, false);
             document.removeEventListener("keyup", keyup, false);
-            held['w'] = held['s'] = held['a'] = held['d'] = false;
+            for (const k in held) held[k] = false;
         }
     }
 
@@ -114,9 +146,27 @@ function fpsControls(dom, pos, dir, update) {
     document.addEventListener('mozpointerlockchange', lockChangeAlert, false);
 }
 
-function initCanvas() {
+// The canvas keeps the size it is displayed at, so the aspect ratio of its
+// pixels follows from the rendering resolution
+function initScreen(mod, size) {
     const canvas = document.getElementById('screen');
-    return canvas.getContext('2d');
+
+    const [width, height] = (size || "").split("x").map(Number);
+    if (width > 0 && height > 0) {
+        canvas.width = width;
+        canvas.height = height;
+    }
+
+    const pixelWidth = canvas.clientWidth / canvas.width;
+    const pixelHeight = canvas.clientHeight / canvas.height;
+
+    return {
+        ctx: canvas.getContext('2d'),
+        width: canvas.width,
+        height: canvas.height,
+        pixelAspect: pixelHeight / pixelWidth,
+        ptr: mod.alloc(canvas.width * canvas.height * 4),
+    };
 }
 
 function copyArrayBuffer(arrayBuffer, buffer, ptr) {
@@ -125,57 +175,187 @@ function copyArrayBuffer(arrayBuffer, buffer, ptr) {
     dst.set(src);
 }
 
-function renderFrame(mod, state, screen, focusPoint, direction) {
-    mod.render(
+function renderFrame(mod, state, screen, focusPoint, direction, viewZ, pitch, fov, colors, tic, weapon, statusBar) {
+    const result = withString(mod, weapon.sprite, (weaponPtr, weaponLen) => withStatusBar(mod, statusBar, statusBarPtr => mod.render(
         state,
         screen.ptr,
+        screen.width, screen.height,
+        screen.pixelAspect,
         focusPoint.x, focusPoint.y,
-        direction.x, direction.y
-    );
+        direction.x, direction.y,
+        viewZ,
+        pitch,
+        fov,
+        colors.palette,
+        colors.gamma,
+        tic,
+        weaponPtr, weaponLen,
+        weapon.speed,
+        statusBarPtr
+    )));
+    if (result !== 0) {
+        console.error(lastError(mod));
+        return;
+    }
 
-    const screenBuf = new Uint8ClampedArray(mod.memory.buffer, screen.ptr, FRAME_BYTE_SIZE);
-    const img = new ImageData(screenBuf, WIDTH, HEIGHT);
+    const frameByteSize = screen.width * screen.height * 4;
+    const screenBuf = new Uint8ClampedArray(mod.memory.buffer, screen.ptr, frameByteSize);
+    const img = new ImageData(screenBuf, screen.width, screen.height);
 
     screen.ctx.putImageData(img, 0, 0);
 }
 
+function readString(mod, str) {
+    const dec = new TextDecoder();
+    const buf = new Uint8Array(mod.memory.buffer, mod.str_buf(str), mod.str_len(str));
+    const text = dec.decode(buf);
+    mod.str_del(str);
+    return text;
+}
+
+function lastError(mod) {
+    const message = readString(mod, mod.last_error_message());
+    return new Error(`${message} (error code ${mod.last_error_code()})`);
+}
+
+function withString(mod, text, callback) {
+    const enc = new TextEncoder();
+    const bytes = enc.encode(text);
+    const ptr = mod.alloc(bytes.byteLength);
+    new Uint8Array(mod.memory.buffer, ptr, bytes.byteLength).set(bytes);
+    const result = callback(ptr, bytes.byteLength);
+    mod.dealloc(ptr, bytes.byteLength);
+    return result;
+}
+
+// Pass the status bar as the 14 little endian i32 values render expects, or
+// null when it is turned off
+function withStatusBar(mod, statusBar, callback) {
+    if (!statusBar.enabled) return callback(0);
+
+    const values = [
+        statusBar.health,
+        statusBar.armor,
+        statusBar.ammo,
+        statusBar.face,
+        0, // No keys
+        0b1, // Only the pistol
+        statusBar.ammo, 200,
+        0, 50,
+        0, 50,
+        0, 300,
+    ];
+    const byteLength = values.length * 4;
+    const ptr = mod.alloc(byteLength);
+    // alloc only aligns to bytes, so write through a DataView
+    const view = new DataView(mod.memory.buffer, ptr, byteLength);
+    values.forEach((value, i) => view.setInt32(i * 4, value, true));
+    const result = callback(ptr);
+    mod.dealloc(ptr, byteLength);
+    return result;
+}
+
 function renderMap(mod, state) {
-    const svg = mod.svg_from_map(state);
-    let dec = new TextDecoder();
-    let svgbuf = new Uint8Array(mod.memory.buffer, mod.str_buf(svg), mod.str_len(svg));
-    let svgtext = dec.decode(svgbuf);
+    const svgtext = readString(mod, mod.svg_from_map(state));
     document.getElementById("map-container").innerHTML = svgtext;
-    mod.str_del(svg);
 }
 
 async function init() {
-    const [wasm, wad] = await Promise.all([
+    // PWADs to load on top of the IWAD can be given as ?pwad=a.wad&pwad=b.wad,
+    // and the rendering resolution as ?size=640x400
+    const params = new URLSearchParams(window.location.search);
+    const pwadUrls = params.getAll("pwad");
+
+    const [wasm, ...wads] = await Promise.all([
         WebAssembly.instantiateStreaming(fetch("wad_render.gc.wasm")),
         fetch("doom1.wad").then(x => x.arrayBuffer()),
+        ...pwadUrls.map(url => fetch(url).then(x => x.arrayBuffer())),
     ]);
 
     const mod = wasm.instance.exports;
 
-    let screen = {
-        ctx: initCanvas(),
-        ptr: mod.alloc(FRAME_BYTE_SIZE),
-    };
+    let screen = initScreen(mod, params.get("size"));
 
-    const wadPtr = mod.alloc(wad.byteLength);
-    copyArrayBuffer(wad, mod.memory.buffer, wadPtr);
-    const wadObject = mod.parse_wad(wadPtr, wad.byteLength);
+    const wadList = mod.wad_list_new();
+    for (const wad of wads) {
+        const wadPtr = mod.alloc(wad.byteLength);
+        copyArrayBuffer(wad, mod.memory.buffer, wadPtr);
+        mod.wad_list_push(wadList, wadPtr, wad.byteLength);
+        mod.dealloc(wadPtr, wad.byteLength);
+    }
+    const wadObject = mod.parse_wad_list(wadList);
+    if (wadObject === 0) throw lastError(mod);
+
+    const mapNames = readString(mod, mod.map_names(wadObject)).split("\n");
 
-    const state = mod.init(wadObject);
+    const state = withString(mod, mapNames[0], (ptr, len) => mod.init(wadObject, ptr, len));
+    if (state === 0) throw lastError(mod);
 
     let focusPoint = { x: mod.spawn_point_x(state), y: mod.spawn_point_y(state) };
     let direction = { x: mod.spawn_point_dx(state), y: mod.spawn_point_dy(state) };
 
+    // Height of the view, or NaN to put it at eye height above the floor
+    let viewZ = NaN;
+
+    // Vertical look angle in radians, positive when looking up
+    let pitch = 0;
+
+    // Horizontal field of view in radians, and the one the map shows
+    const fovInput = document.getElementById("fov");
+    let fov = fovInput.value * Math.PI / 180;
+    let shownFov = fov;
+
+    // PLAYPAL palette and gamma correction level
+    const paletteInput = document.getElementById("palette");
+    const gammaInput = document.getElementById("gamma");
+    const colors = { palette: Number(paletteInput.value), gamma: Number(gammaInput.value) };
+
+    // Weapon sprite lump, empty for none, and the player speed it bobs with
+    const weaponSelect = document.getElementById("weapon");
+    const weapon = { sprite: weaponSelect.value, speed: 0 };
+
+    // What the status bar shows, if it is turned on
+    const statusBarInput = document.getElementById("status-bar");
+    const healthInput = document.getElementById("health");
+    const armorInput = document.getElementById("armor");
+    const ammoInput = document.getElementById("ammo");
+    const faceSelect = document.getElementById("face");
+    const statusBar = {};
+    function readStatusBar() {
+        statusBar.enabled = statusBarInput.checked;
+        statusBar.health = Number(healthInput.value);
+        statusBar.armor = Number(armorInput.value);
+        statusBar.ammo = Number(ammoInput.value);
+        statusBar.face = Number(faceSelect.value);
+    }
+    readStatusBar();
+
+    function resetCamera() {
+        if (mod.camera_reset(state, focusPoint.x, focusPoint.y) !== 0) {
+            console.error(lastError(mod));
+        }
+        viewZ = NaN;
+    }
+    resetCamera();
+
     // --- --- ---
 
+    // The game tic animated textures are shown at. It stands still while
+    // animation is turned off.
+    const animateInput = document.getElementById("animate");
+    let tic = 0;
+    let ticStart = performance.now();
+
     let pendingRender = false;
     function render(_timestamp) {
         pendingRender = false;
-        renderFrame(mod, state, screen, focusPoint, direction);
+        renderFrame(mod, state, screen, focusPoint, direction, viewZ, pitch, fov, colors, tic, weapon, statusBar);
+
+        // Show the field of view that was actually used, after limiting
+        if (mod.fov(state) !== shownFov) {
+            shownFov = mod.fov(state);
+            mapApi.updateCamera(focusPoint, direction, shownFov);
+        }
     }
 
     function scheduleRender() {
@@ -194,16 +374,87 @@ async function init() {
 
     // --- --- ---
 
-    renderMap(mod, state);
-    let mapRoot = document.getElementById("map-root");
-    let mapApi = interactiveMap(mapRoot, { focusPoint, direction }, updateCamera);
+    let mapApi = null;
+    function showMap() {
+        renderMap(mod, state);
+        let mapRoot = document.getElementById("map-root");
+        mapApi = interactiveMap(mapRoot, { focusPoint, direction, fov: shownFov }, (focusPoint, direction) => {
+            // Moving on the map is a teleport, so the camera does not fall
+            updateCamera(focusPoint, direction);
+            resetCamera();
+        });
+    }
+    showMap();
+
+    const mapSelect = document.getElementById("map-select");
+    for (const mapName of mapNames) {
+        const option = document.createElement("option");
+        option.textContent = mapName;
+        mapSelect.appendChild(option);
+    }
+    mapSelect.addEventListener("change", () => {
+        const result = withString(mod, mapSelect.value, (ptr, len) => mod.set_map(state, ptr, len));
+        if (result !== 0) {
+            alert(lastError(mod));
+            return;
+        }
+
+        focusPoint.x = mod.spawn_point_x(state);
+        focusPoint.y = mod.spawn_point_y(state);
+        direction.x = mod.spawn_point_dx(state);
+        direction.y = mod.spawn_point_dy(state);
+        pitch = 0;
+        resetCamera();
+
+        showMap();
+        scheduleRender();
+    });
 
     // --- --- ---
 
+    const moveResult = mod.alloc(12);
+    function movePlayer(pos, movement) {
+        const result = mod.move_player(
+            state,
+            pos.x, pos.y,
+            movement.x, movement.y,
+            PLAYER_RADIUS,
+            moveResult
+        );
+        if (result !== 0) {
+            console.error(lastError(mod));
+            return pos;
+        }
+
+        const out = new DataView(mod.memory.buffer, moveResult, 12);
+        return { x: out.getFloat32(0, true), y: out.getFloat32(4, true) };
+    }
+
+    const camera = {
+        tick(pos, seconds) {
+            viewZ = mod.camera_update(state, pos.x, pos.y, seconds);
+            return mod.camera_is_moving(state) !== 0;
+        },
+        setMode(mode) {
+            mod.camera_set_mode(state, mode);
+        },
+        fly(direction) {
+            mod.camera_set_fly_direction(state, direction);
+        },
+        look(delta) {
+            pitch = Math.max(-MAX_PITCH, Math.min(MAX_PITCH, pitch + delta));
+        },
+        setSpeed(speed) {
+            weapon.speed = speed;
+        },
+    };
+
     fpsControls(
         document.getElementById('screen'),
         () => focusPoint,
         () => direction,
+        movePlayer,
+        camera,
         (focusPoint, direction) => {
             mapApi.updateCamera(focusPoint, direction);
             updateCamera(focusPoint, direction);
@@ -212,7 +463,46 @@ async function init() {
 
     // --- --- ---
 
-    renderFrame(mod, state, screen, focusPoint, direction);
+    fovInput.addEventListener("input", () => {
+        fov = fovInput.value * Math.PI / 180;
+        document.getElementById("fov-value").textContent = fovInput.value;
+        scheduleRender();
+    });
+
+    for (const input of [paletteInput, gammaInput]) {
+        input.addEventListener("input", () => {
+            colors.palette = Number(paletteInput.value);
+            colors.gamma = Number(gammaInput.value);
+            scheduleRender();
+        });
+    }
+
+    weaponSelect.addEventListener("change", () => {
+        weapon.sprite = weaponSelect.value;
+        scheduleRender();
+    });
+
+    for (const input of [statusBarInput, healthInput, armorInput, ammoInput, faceSelect]) {
+        input.addEventListener("input", () => {
+            readStatusBar();
+            scheduleRender();
+        });
+    }
+
+    setInterval(() => {
+        if (!animateInput.checked) {
+            ticStart = performance.now() - tic * 1000 / TICS_PER_SECOND;
+            return;
+        }
+
+        const newTic = Math.floor((performance.now() - ticStart) * TICS_PER_SECOND / 1000);
+        if (newTic !== tic) {
+            tic = newTic;
+            scheduleRender();
+        }
+    }, 1000 / TICS_PER_SECOND);
+
+    render();
 }
 
 init()
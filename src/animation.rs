@@ -0,0 +1,223 @@
+//! Animated textures and flats, following vanilla's `P_InitPicAnims` and
+//! `P_UpdateSpecials`
+
+use crate::util::lump_name;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use wad::EntryId;
+
+// Tics each frame is shown in the vanilla animations
+const VANILLA_SPEED: u32 = 8;
+
+const ANIMATED_ENTRY_SIZE: usize = 23;
+const ANIMATED_END: u8 = 0xff;
+
+/// An animation cycling through the textures or flats from `first` to
+/// `last`, in the order they appear in the WAD
+#[derive(Debug, PartialEq)]
+pub struct AnimDef {
+    pub is_texture: bool,
+    pub first: [u8; 8],
+    pub last: [u8; 8],
+    pub speed: u32,
+}
+
+// Vanilla's animdefs as (is_texture, last, first)
+const VANILLA_ANIMDEFS: &[(bool, &[u8], &[u8])] = &[
+    (false, b"NUKAGE3", b"NUKAGE1"),
+    (false, b"FWATER4", b"FWATER1"),
+    (false, b"SWATER4", b"SWATER1"),
+    (false, b"LAVA4", b"LAVA1"),
+    (false, b"BLOOD3", b"BLOOD1"),
+    // Doom II
+    (false, b"RROCK08", b"RROCK05"),
+    (false, b"SLIME04", b"SLIME01"),
+    (false, b"SLIME08", b"SLIME05"),
+    (false, b"SLIME12", b"SLIME09"),
+    (true, b"BLODGR4", b"BLODGR1"),
+    (true, b"SLADRIP3", b"SLADRIP1"),
+    (true, b"BLODRIP4", b"BLODRIP1"),
+    (true, b"FIREWALL", b"FIREWALA"),
+    (true, b"GSTFONT3", b"GSTFONT1"),
+    (true, b"FIRELAV3", b"FIRELAVA"),
+    (true, b"FIREMAG3", b"FIREMAG1"),
+    (true, b"FIREBLU2", b"FIREBLU1"),
+    (true, b"ROCKRED3", b"ROCKRED1"),
+    (true, b"BFALL4", b"BFALL1"),
+    (true, b"SFALL4", b"SFALL1"),
+    (true, b"WFALL4", b"WFALL1"),
+    (true, b"DBRAIN4", b"DBRAIN1"),
+];
+
+pub fn vanilla_animdefs() -> Vec<AnimDef> {
+    VANILLA_ANIMDEFS
+        .iter()
+        .map(|&(is_texture, last, first)| AnimDef {
+            is_texture,
+            first: lump_name(first),
+            last: lump_name(last),
+            speed: VANILLA_SPEED,
+        })
+        .collect()
+}
+
+/// Parse Boom's ANIMATED lump, which replaces the vanilla animations. Each
+/// entry is a type byte, the last and first names in 9 bytes each and the
+/// speed in tics. A type of 0xff ends the list.
+pub fn parse_animated(lump: &[u8]) -> Vec<AnimDef> {
+    lump.chunks_exact(ANIMATED_ENTRY_SIZE)
+        .take_while(|entry| entry[0] != ANIMATED_END)
+        .map(|entry| AnimDef {
+            is_texture: entry[0] & 1 != 0,
+            last: lump_name(&entry[1..10]),
+            first: lump_name(&entry[10..19]),
+            speed: u32::from_le_bytes(entry[19..23].try_into().unwrap()).max(1),
+        })
+        .collect()
+}
+
+/// The animations of the WAD, from ANIMATED if it has one
+pub fn animdefs(wad: &wad::WadSlice) -> Vec<AnimDef> {
+    match wad.by_id(b"ANIMATED") {
+        Some(lump) => parse_animated(lump),
+        None => vanilla_animdefs(),
+    }
+}
+
+struct Cycle {
+    // Index of the first frame among all textures or flats
+    base: usize,
+    frames: Vec<EntryId>,
+    speed: u32,
+}
+
+/// Resolves animated textures or flats to the frame shown at a given tic
+#[derive(Default)]
+pub struct Animations {
+    cycles: Vec<Cycle>,
+    // The cycle and position in it of every animated name
+    positions: HashMap<EntryId, (usize, usize)>,
+}
+
+impl Animations {
+    /// Set up the given animations. `index_of` finds a texture or flat by
+    /// name, and `name_at` gives the name at an index. Animations with
+    /// missing ends or fewer than two frames are skipped, as in vanilla.
+    pub fn new<'d>(
+        defs: impl IntoIterator<Item = &'d AnimDef>,
+        index_of: impl Fn(&[u8; 8]) -> Option<usize>,
+        name_at: impl Fn(usize) -> Option<EntryId>,
+    ) -> Animations {
+        let mut animations = Animations::default();
+
+        for def in defs {
+            let (first, last) = match (index_of(&def.first), index_of(&def.last)) {
+                (Some(first), Some(last)) if last > first => (first, last),
+                _ => continue,
+            };
+
+            let frames = match (first..=last).map(&name_at).collect::<Option<Vec<_>>>() {
+                Some(frames) => frames,
+                None => continue,
+            };
+
+            let cycle = animations.cycles.len();
+            for (position, &frame) in frames.iter().enumerate() {
+                animations.positions.insert(frame, (cycle, position));
+            }
+
+            animations.cycles.push(Cycle {
+                base: first,
+                frames,
+                speed: def.speed,
+            });
+        }
+
+        animations
+    }
+
+    /// The frame shown in place of `id` at the given tic
+    pub fn frame(&self, id: EntryId, tic: u32) -> EntryId {
+        match self.positions.get(&id) {
+            Some(&(cycle, position)) => {
+                let cycle = &self.cycles[cycle];
+                let step = (tic / cycle.speed) as usize;
+                cycle.frames[(step + cycle.base + position) % cycle.frames.len()]
+            }
+            None => id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn animated_entry(is_texture: bool, last: &[u8], first: &[u8], speed: u32) -> Vec<u8> {
+        let mut entry = vec![is_texture as u8];
+        for name in &[last, first] {
+            let mut field = [0u8; 9];
+            field[..name.len()].copy_from_slice(name);
+            entry.extend_from_slice(&field);
+        }
+        entry.extend_from_slice(&speed.to_le_bytes());
+        entry
+    }
+
+    #[test]
+    fn animated_lump() {
+        let mut lump = animated_entry(false, b"NUKAGE3", b"NUKAGE1", 8);
+        lump.extend(animated_entry(true, b"blodgr4", b"blodgr1", 4));
+        lump.push(ANIMATED_END);
+
+        assert_eq!(
+            parse_animated(&lump),
+            [
+                AnimDef {
+                    is_texture: false,
+                    first: *b"NUKAGE1\0",
+                    last: *b"NUKAGE3\0",
+                    speed: 8,
+                },
+                AnimDef {
+                    is_texture: true,
+                    first: *b"BLODGR1\0",
+                    last: *b"BLODGR4\0",
+                    speed: 4,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn cycle() {
+        let names = [
+            lump_name(b"WALL"),
+            lump_name(b"FALL1"),
+            lump_name(b"FALL2"),
+            lump_name(b"FALL3"),
+        ];
+        let defs = [AnimDef {
+            is_texture: true,
+            first: lump_name(b"FALL1"),
+            last: lump_name(b"FALL3"),
+            speed: 8,
+        }];
+
+        let animations = Animations::new(
+            &defs,
+            |name| names.iter().position(|x| x == name),
+            |i| names.get(i).map(EntryId::from_bytes),
+        );
+
+        let frame = |name: &[u8], tic| animations.frame(EntryId::from_bytes(&lump_name(name)), tic);
+        let id = |name: &[u8]| EntryId::from_bytes(&lump_name(name));
+
+        // Vanilla offsets the cycle by the index of its first frame
+        assert!(frame(b"FALL1", 0) == id(b"FALL2"));
+        assert!(frame(b"FALL1", 7) == id(b"FALL2"));
+        assert!(frame(b"FALL1", 8) == id(b"FALL3"));
+        assert!(frame(b"FALL3", 8) == id(b"FALL2"));
+        assert!(frame(b"WALL", 8) == id(b"WALL"));
+    }
+}
@@ -0,0 +1,156 @@
+use std::convert::{TryFrom, TryInto};
+
+const BLOCK_SIZE: i32 = 128;
+const END_OF_LIST: u16 = 0xffff;
+
+/// Grid of 128x128 blocks over the map, listing the linedefs that pass
+/// through each block
+pub struct Blockmap {
+    x: i32,
+    y: i32,
+    columns: i32,
+    rows: i32,
+    blocks: Vec<Vec<u16>>,
+}
+
+fn read_u16(buf: &[u8], index: usize) -> Option<u16> {
+    buf.get(index * 2..index * 2 + 2)
+        .map(|x| u16::from_le_bytes(x.try_into().unwrap()))
+}
+
+impl Blockmap {
+    /// Parse a BLOCKMAP lump. Returns `None` if it is malformed or refers to
+    /// linedefs that do not exist.
+    pub fn parse(lump: &[u8], num_linedefs: usize) -> Option<Blockmap> {
+        let x = read_u16(lump, 0)? as i16 as i32;
+        let y = read_u16(lump, 1)? as i16 as i32;
+        let columns = read_u16(lump, 2)? as i32;
+        let rows = read_u16(lump, 3)? as i32;
+
+        let num_blocks = (columns as usize).checked_mul(rows as usize)?;
+        let blocks = (0..num_blocks)
+            .map(|i| {
+                let mut offset = read_u16(lump, 4 + i)? as usize;
+                let mut lines = vec![];
+
+                // Every list starts with a 0 that is not a reference to a line
+                offset += 1;
+
+                loop {
+                    match read_u16(lump, offset)? {
+                        END_OF_LIST => break,
+                        line if (line as usize) < num_linedefs => lines.push(line),
+                        _ => return None,
+                    }
+                    offset += 1;
+                }
+
+                Some(lines)
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        Some(Blockmap {
+            x,
+            y,
+            columns,
+            rows,
+            blocks,
+        })
+    }
+
+    /// Build a blockmap for maps that have none. Lines are added to every
+    /// block their bounding box touches, which may include some blocks the
+    /// line does not actually cross. Returns `None` if the map is too large
+    /// for the grid.
+    pub fn build(map: &wad_map::Map) -> Option<Blockmap> {
+        let bbox = wad_map::BoundingBox::from(&map.vertexes);
+        let (x, y) = (bbox.left() as i32, bbox.bottom() as i32);
+        let columns = (bbox.right() as i32 - x) / BLOCK_SIZE + 1;
+        let rows = (bbox.top() as i32 - y) / BLOCK_SIZE + 1;
+
+        let num_blocks = (columns as usize).checked_mul(rows as usize)?;
+        let mut blockmap = Blockmap {
+            x,
+            y,
+            columns,
+            rows,
+            blocks: vec![vec![]; num_blocks],
+        };
+
+        for (i, linedef) in map.linedefs.iter().enumerate() {
+            let a = &map.vertexes[linedef.a as usize];
+            let b = &map.vertexes[linedef.b as usize];
+
+            let (left, right) = (a.x.min(b.x) as f32, a.x.max(b.x) as f32);
+            let (bottom, top) = (a.y.min(b.y) as f32, a.y.max(b.y) as f32);
+
+            for block in blockmap.block_indexes(left, bottom, right, top) {
+                blockmap.blocks[block].push(u16::try_from(i).ok()?);
+            }
+        }
+
+        Some(blockmap)
+    }
+
+    fn block_indexes(&self, left: f32, bottom: f32, right: f32, top: f32) -> Vec<usize> {
+        let column = |x: f32| {
+            ((x as i32 - self.x).div_euclid(BLOCK_SIZE))
+                .max(0)
+                .min(self.columns - 1)
+        };
+        let row = |y: f32| {
+            ((y as i32 - self.y).div_euclid(BLOCK_SIZE))
+                .max(0)
+                .min(self.rows - 1)
+        };
+
+        (row(bottom)..=row(top))
+            .flat_map(|r| {
+                (column(left)..=column(right))
+                    .map(move |c| r as usize * self.columns as usize + c as usize)
+            })
+            .collect()
+    }
+
+    /// The linedefs in the blocks touched by the given box, each listed once
+    pub fn lines_in(&self, left: f32, bottom: f32, right: f32, top: f32) -> Vec<u16> {
+        let mut lines = self
+            .block_indexes(left, bottom, right, top)
+            .into_iter()
+            .flat_map(|block| self.blocks[block].iter().cloned())
+            .collect::<Vec<_>>();
+
+        lines.sort_unstable();
+        lines.dedup();
+        lines
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::*;
+
+    #[test]
+    fn parse_and_build() {
+        let wad = room_wad();
+        let map = wad_map::read_map(&wad.as_slice(), "E1M1").unwrap();
+        let lump = wad.as_slice().by_id(b"BLOCKMAP").unwrap();
+
+        let parsed = Blockmap::parse(lump, map.linedefs.len()).unwrap();
+        let built = Blockmap::build(&map).unwrap();
+
+        assert_eq!(parsed.lines_in(0., 0., 256., 256.), [0, 1, 2, 3]);
+        assert_eq!(built.lines_in(0., 0., 256., 256.), [0, 1, 2, 3]);
+
+        // The lower left block holds the west and south walls
+        assert_eq!(built.lines_in(8., 8., 16., 16.), [0, 3]);
+
+        assert!(Blockmap::parse(lump, 2).is_none());
+        assert!(Blockmap::parse(&lump[..10], map.linedefs.len()).is_none());
+
+        // The largest grid, with a header and nothing else
+        let huge = [0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff];
+        assert!(Blockmap::parse(&huge, map.linedefs.len()).is_none());
+    }
+}
@@ -1,45 +1,337 @@
-use cgmath::{vec2, Vector2};
+use crate::util::*;
+use cgmath::{vec2, Matrix2, Vector2};
+use std::ops::Range;
+
+// Bounding box as (left, bottom, right, top)
+type Bounds = (f32, f32, f32, f32);
+
+/// The part of the map that can be seen, used to skip subtrees that are
+/// outside of it
+pub struct View {
+    pub pos: Vector2<f32>,
+    pub dir: Vector2<f32>,
+    /// Horizontal field of view in radians
+    pub fov: f32,
+    /// Width of the screen in pixels
+    pub width: i32,
+}
 
 pub struct BspTraverser<'a> {
     nodes: &'a [wad_map::Node],
     pos: Vector2<f32>,
-    state: Vec<wad_map::Child>,
+    view: Option<(Matrix2<f32>, f32, i32)>,
+    // Children yet to be visited, with their bounding boxes. The root has
+    // none and is always visited.
+    state: Vec<(wad_map::Child, Option<Bounds>)>,
 }
 
 impl<'a> BspTraverser<'a> {
     pub fn new(nodes: &'a [wad_map::Node], pos: Vector2<f32>) -> BspTraverser<'a> {
+        // A map that is a single subsector has no nodes
+        let root = match nodes.len() {
+            0 => wad_map::Child::Subsector(0),
+            n => ((n - 1) as u16).into(),
+        };
+
         BspTraverser {
             nodes,
             pos,
-            state: vec![((nodes.len() - 1) as u16).into()],
+            view: None,
+            state: vec![(root, None)],
+        }
+    }
+
+    /// Traverse the nodes as seen from the given view. Use `next_visible`
+    /// to skip subtrees that cannot be seen.
+    pub fn with_view(nodes: &'a [wad_map::Node], view: &View) -> BspTraverser<'a> {
+        let transform = Matrix2::new(view.dir.y, view.dir.x, -view.dir.x, view.dir.y);
+        let tan = (view.fov / 2.).tan();
+
+        BspTraverser {
+            view: Some((transform, tan, view.width)),
+            ..BspTraverser::new(nodes, view.pos)
+        }
+    }
+
+    /// Screen columns covered by the given bounding box, or `None` if it is
+    /// entirely outside the view
+    fn screen_span(&self, (left, bottom, right, top): Bounds) -> Option<Range<i32>> {
+        let (transform, tan, width) = match self.view {
+            Some(view) => view,
+            None => return Some(0..i32::MAX),
+        };
+
+        let inside = (left..=right).contains(&self.pos.x) && (bottom..=top).contains(&self.pos.y);
+        if inside {
+            return Some(0..width);
+        }
+
+        let corners = [
+            vec2(left, bottom),
+            vec2(right, bottom),
+            vec2(right, top),
+            vec2(left, top),
+        ];
+        let mut polygon = corners
+            .iter()
+            .map(|&p| transform * (p - self.pos))
+            .collect::<Vec<_>>();
+
+        // Clip to the frustum. Each plane is given as a function that is
+        // non-negative on the inside
+        let planes: [&dyn Fn(Vector2<f32>) -> f32; 3] =
+            [&|p| p.y - 1., &|p| p.y * tan - p.x, &|p| p.y * tan + p.x];
+
+        for plane in &planes {
+            polygon = clip_polygon(&polygon, plane);
+            if polygon.is_empty() {
+                return None;
+            }
+        }
+
+        let center_x = width as f32 / 2.;
+        let projection = center_x / tan;
+        let screen_x = |p: &Vector2<f32>| center_x + projection * p.x / p.y;
+
+        let min = polygon
+            .iter()
+            .map(screen_x)
+            .fold(std::f32::INFINITY, f32::min);
+        let max = polygon
+            .iter()
+            .map(screen_x)
+            .fold(std::f32::NEG_INFINITY, f32::max);
+
+        Some(intersect(
+            min.floor() as i32..max.ceil() as i32 + 1,
+            0..width,
+        ))
+    }
+
+    fn is_visible(&self, bbox: Bounds, h_open: &[Range<i32>]) -> bool {
+        match self.screen_span(bbox) {
+            Some(span) => h_open
+                .iter()
+                .any(|open| !is_empty(&intersect(open.clone(), span.clone()))),
+            None => false,
         }
     }
+
+    /// The next subsector, front to back, skipping subtrees that are outside
+    /// of the view or hidden behind the columns that are already drawn.
+    /// `h_open` is the set of screen columns that are still open.
+    ///
+    /// As in `R_RenderBSPNode`, a subtree is only tested when it is reached,
+    /// so that everything in front of it has been drawn by then.
+    pub fn next_visible(&mut self, h_open: &[Range<i32>]) -> Option<u16> {
+        loop {
+            let (child, bbox) = self.state.pop()?;
+
+            if let Some(bbox) = bbox {
+                if !self.is_visible(bbox, h_open) {
+                    continue;
+                }
+            }
+
+            match child {
+                wad_map::Child::Subsector(s) => return Some(s),
+                wad_map::Child::Subnode(n) => {
+                    let node = &self.nodes[n as usize];
+                    let (front, back) = self.children(node);
+
+                    for (child, bbox) in [back, front] {
+                        self.state.push((child, Some(bbox)));
+                    }
+                }
+            }
+        }
+    }
+
+    /// The children of a node with their bounding boxes, ordered front to back
+    fn children(
+        &self,
+        node: &wad_map::Node,
+    ) -> ((wad_map::Child, Bounds), (wad_map::Child, Bounds)) {
+        let bbox = |b: &wad_map::BoundingBox| {
+            (
+                b.left() as f32,
+                b.bottom() as f32,
+                b.right() as f32,
+                b.top() as f32,
+            )
+        };
+
+        let left = (node.left_child.clone(), bbox(&node.left_bounding_box));
+        let right = (node.right_child.clone(), bbox(&node.right_bounding_box));
+
+        if self.is_right_side(node) {
+            (right, left)
+        } else {
+            (left, right)
+        }
+    }
+
+    fn is_right_side(&self, node: &wad_map::Node) -> bool {
+        let view = self.pos - vec2(node.x as f32, node.y as f32);
+        let left = node.dy as f32 * view.x;
+        let right = view.y * node.dx as f32;
+
+        right < left
+    }
+}
+
+/// Clip a convex polygon to the part where `plane` is non-negative
+fn clip_polygon(
+    polygon: &[Vector2<f32>],
+    plane: &dyn Fn(Vector2<f32>) -> f32,
+) -> Vec<Vector2<f32>> {
+    let mut clipped = vec![];
+
+    for (i, &a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+        let (da, db) = (plane(a), plane(b));
+
+        if da >= 0. {
+            clipped.push(a);
+        }
+        if (da >= 0.) != (db >= 0.) {
+            clipped.push(a + (b - a) * (da / (da - db)));
+        }
+    }
+
+    clipped
 }
 
 impl<'a> Iterator for BspTraverser<'a> {
     type Item = u16;
 
     fn next(&mut self) -> Option<u16> {
-        match self.state.pop()? {
+        match self.state.pop()?.0 {
             wad_map::Child::Subsector(s) => Some(s),
             wad_map::Child::Subnode(n) => {
                 let node = &self.nodes[n as usize];
+                let (front, back) = self.children(node);
 
-                let view = self.pos - vec2(node.x as f32, node.y as f32);
-                let left = node.dy as f32 * view.x;
-                let right = view.y * node.dx as f32;
+                self.state.push((back.0, None));
+                self.state.push((front.0, None));
+                self.next()
+            }
+        }
+    }
+}
 
-                let is_right_side = right < left;
+/// The sector containing the given point, if any
+pub fn sector_at(map: &wad_map::Map, pos: Vector2<f32>) -> Option<&wad_map::Sector> {
+    for subsector in BspTraverser::new(&map.nodes, pos) {
+        let subsector = &map.subsectors[subsector as usize];
 
-                if is_right_side {
-                    self.state.push(node.left_child.clone());
-                    self.state.push(node.right_child.clone());
-                } else {
-                    self.state.push(node.right_child.clone());
-                    self.state.push(node.left_child.clone());
-                }
-                self.next()
+        let start = subsector.first_seg as usize;
+        let end = start + subsector.seg_count as usize;
+
+        for line_segment in &map.line_segments[start..end] {
+            let linedef = &map.linedefs[line_segment.linedef as usize];
+
+            let a = &map.vertexes[line_segment.start_vertex as usize];
+            let b = &map.vertexes[line_segment.end_vertex as usize];
+
+            let a = vec2(a.x as f32, a.y as f32);
+            let b = vec2(b.x as f32, b.y as f32);
+
+            let reverse = line_segment.direction != 0;
+            let right_side = ((pos - a).perp_dot(b - a) > 0.) ^ reverse;
+
+            let front_sidedef = if right_side {
+                linedef.right_sidedef
+            } else {
+                linedef.left_sidedef
+            };
+
+            if let Some(front_sidedef) = front_sidedef {
+                let front_sidedef = &map.sidedefs[front_sidedef as usize];
+                return Some(&map.sectors[front_sidedef.sector_id as usize]);
             }
         }
     }
+
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::*;
+
+    #[test]
+    fn front_to_back() {
+        let wad = room_wad();
+        let map = wad_map::read_map(&wad.as_slice(), "E1M1").unwrap();
+
+        let east = BspTraverser::new(&map.nodes, vec2(200., 128.)).collect::<Vec<_>>();
+        assert_eq!(east, [0, 1]);
+
+        let west = BspTraverser::new(&map.nodes, vec2(32., 128.)).collect::<Vec<_>>();
+        assert_eq!(west, [1, 0]);
+    }
+
+    fn visible(map: &wad_map::Map, view: &View, h_open: &[Range<i32>]) -> Vec<u16> {
+        let mut traverser = BspTraverser::with_view(&map.nodes, view);
+        std::iter::from_fn(|| traverser.next_visible(h_open)).collect()
+    }
+
+    #[test]
+    fn frustum_culling() {
+        let wad = room_wad();
+        let map = wad_map::read_map(&wad.as_slice(), "E1M1").unwrap();
+        let fov = std::f32::consts::PI / 2.;
+
+        let facing_east = View {
+            pos: vec2(200., 128.),
+            dir: vec2(1., 0.),
+            fov,
+            width: 320,
+        };
+        assert_eq!(visible(&map, &facing_east, &[0..320]), [0]);
+
+        let facing_west = View {
+            dir: vec2(-1., 0.),
+            ..facing_east
+        };
+        assert_eq!(visible(&map, &facing_west, &[0..320]), [0, 1]);
+    }
+
+    #[test]
+    fn occlusion_culling() {
+        let wad = room_wad();
+        let map = wad_map::read_map(&wad.as_slice(), "E1M1").unwrap();
+
+        // Looking west from east of the room, the west half covers the
+        // middle of the screen
+        let view = View {
+            pos: vec2(330., 128.),
+            dir: vec2(-1., 0.),
+            fov: std::f32::consts::PI / 2.,
+            width: 320,
+        };
+        assert_eq!(visible(&map, &view, &[0..10, 310..320]), [0]);
+        assert_eq!(visible(&map, &view, &[150..170]), [0, 1]);
+    }
+
+    #[test]
+    fn hidden_by_front() {
+        let wad = room_wad();
+        let map = wad_map::read_map(&wad.as_slice(), "E1M1").unwrap();
+
+        let view = View {
+            pos: vec2(200., 128.),
+            dir: vec2(-1., 0.),
+            fov: std::f32::consts::PI / 2.,
+            width: 320,
+        };
+        let mut traverser = BspTraverser::with_view(&map.nodes, &view);
+
+        // Once the near subsector has closed every column, the one behind
+        // it is culled
+        assert_eq!(traverser.next_visible(&[0..320]), Some(0));
+        assert_eq!(traverser.next_visible(&[]), None);
+    }
 }
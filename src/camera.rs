@@ -0,0 +1,280 @@
+//! Vertical camera movement, following vanilla's `P_ZMovement` and
+//! `P_CalcHeight`. All speeds are per tic, at 35 tics per second.
+
+pub const TICS_PER_SECOND: f32 = 35.;
+
+/// Vanilla's VIEWHEIGHT, the eye height above the floor
+pub const VIEW_HEIGHT: f32 = 41.;
+
+const PLAYER_HEIGHT: f32 = 56.;
+const GRAVITY: f32 = 1.;
+
+// The view must stay this far below the ceiling
+const CEILING_CLEARANCE: f32 = 4.;
+
+// How fast the view lowers when crouching, and the speed of flying
+const CROUCH_SPEED: f32 = 2.;
+const FLY_SPEED: f32 = 8.;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Walk,
+    Crouch,
+    Fly,
+}
+
+impl Mode {
+    pub fn from_code(code: i32) -> Option<Mode> {
+        match code {
+            0 => Some(Mode::Walk),
+            1 => Some(Mode::Crouch),
+            2 => Some(Mode::Fly),
+            _ => None,
+        }
+    }
+}
+
+/// The vertical state of the camera. Horizontal movement is handled by
+/// `Collision`.
+pub struct Camera {
+    /// Height of the feet
+    pub z: f32,
+    momentum_z: f32,
+
+    view_height: f32,
+    delta_view_height: f32,
+
+    /// Eye height above the floor when walking. Crouching halves it.
+    pub eye_height: f32,
+    mode: Mode,
+
+    // Vertical flying direction, -1, 0 or 1
+    fly_direction: f32,
+
+    // Time not yet simulated, in tics
+    pending_tics: f32,
+}
+
+impl Camera {
+    /// A camera standing on the given floor
+    pub fn new(floor: f32) -> Camera {
+        Camera {
+            z: floor,
+            momentum_z: 0.,
+            view_height: VIEW_HEIGHT,
+            delta_view_height: 0.,
+            eye_height: VIEW_HEIGHT,
+            mode: Mode::Walk,
+            fly_direction: 0.,
+            pending_tics: 0.,
+        }
+    }
+
+    /// Put the camera back on the given floor, as when teleporting. The eye
+    /// height and mode are kept.
+    pub fn reset(&mut self, floor: f32) {
+        self.z = floor;
+        self.momentum_z = 0.;
+        self.view_height = self.target_view_height();
+        self.delta_view_height = 0.;
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        self.momentum_z = 0.;
+
+        // Standing up rises smoothly, like stepping up
+        if self.view_height < self.target_view_height() {
+            self.delta_view_height = (self.target_view_height() - self.view_height) / 8.;
+        }
+    }
+
+    /// Fly up for a positive direction and down for a negative one, while in
+    /// fly mode
+    pub fn set_fly_direction(&mut self, direction: f32) {
+        self.fly_direction = direction.max(-1.).min(1.);
+    }
+
+    fn target_view_height(&self) -> f32 {
+        match self.mode {
+            Mode::Crouch => self.eye_height / 2.,
+            Mode::Walk | Mode::Fly => self.eye_height,
+        }
+    }
+
+    /// Height of the eyes
+    pub fn view_z(&self, ceil: f32) -> f32 {
+        (self.z + self.view_height).min(ceil - CEILING_CLEARANCE)
+    }
+
+    /// Whether another tic would change anything, so callers know to keep
+    /// animating
+    pub fn is_moving(&self) -> bool {
+        self.momentum_z != 0.
+            || self.delta_view_height != 0.
+            || self.view_height != self.target_view_height()
+            || (self.mode == Mode::Fly && self.fly_direction != 0.)
+    }
+
+    fn z_movement(&mut self, floor: f32, ceil: f32) {
+        if self.mode == Mode::Fly {
+            self.z += self.fly_direction * FLY_SPEED;
+        } else {
+            // Smooth out stepping up onto a higher floor
+            if self.z < floor {
+                self.view_height -= floor - self.z;
+                self.delta_view_height = (self.target_view_height() - self.view_height) / 8.;
+            }
+
+            self.z += self.momentum_z;
+        }
+
+        if self.z <= floor {
+            // Squat down when hitting the ground hard
+            if self.momentum_z < -GRAVITY * 8. {
+                self.delta_view_height = self.momentum_z / 8.;
+            }
+
+            self.z = floor;
+            self.momentum_z = 0.;
+        } else if self.mode != Mode::Fly {
+            self.momentum_z -= if self.momentum_z == 0. {
+                GRAVITY * 2.
+            } else {
+                GRAVITY
+            };
+        }
+
+        if self.z + PLAYER_HEIGHT > ceil {
+            self.z = (ceil - PLAYER_HEIGHT).max(floor);
+            self.momentum_z = self.momentum_z.min(0.);
+        }
+    }
+
+    fn calc_height(&mut self) {
+        let target = self.target_view_height();
+
+        self.view_height += self.delta_view_height;
+
+        if self.view_height > target {
+            // Lowering the view is not smoothed by vanilla, but crouching is
+            self.view_height = if self.delta_view_height == 0. {
+                (self.view_height - CROUCH_SPEED).max(target)
+            } else {
+                target
+            };
+            self.delta_view_height = 0.;
+        }
+
+        if self.view_height < target / 2. {
+            self.view_height = target / 2.;
+            if self.delta_view_height <= 0. {
+                self.delta_view_height = 1. / 65536.;
+            }
+        }
+
+        if self.delta_view_height != 0. {
+            self.delta_view_height += 0.25;
+            if self.delta_view_height == 0. {
+                self.delta_view_height = 1. / 65536.;
+            }
+        }
+    }
+
+    /// Advance one tic with the given floor and ceiling heights below and
+    /// above the camera
+    pub fn tick(&mut self, floor: f32, ceil: f32) {
+        self.z_movement(floor, ceil);
+        self.calc_height();
+    }
+
+    /// Advance by the given time in seconds. Time that does not add up to a
+    /// whole tic is carried over to the next call.
+    pub fn advance(&mut self, floor: f32, ceil: f32, seconds: f32) {
+        self.pending_tics += seconds * TICS_PER_SECOND;
+
+        while self.pending_tics >= 1. {
+            self.tick(floor, ceil);
+            self.pending_tics -= 1.;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CEIL: f32 = 256.;
+
+    #[test]
+    fn standing() {
+        let mut camera = Camera::new(0.);
+        camera.tick(0., CEIL);
+
+        assert_eq!(camera.view_z(CEIL), VIEW_HEIGHT);
+        assert!(!camera.is_moving());
+    }
+
+    #[test]
+    fn step_up() {
+        let mut camera = Camera::new(0.);
+
+        camera.tick(24., CEIL);
+        assert_eq!(camera.z, 24.);
+        assert!(camera.view_z(CEIL) < 24. + VIEW_HEIGHT);
+
+        let mut previous = camera.view_z(CEIL);
+        for _ in 0..35 {
+            camera.tick(24., CEIL);
+            assert!(camera.view_z(CEIL) >= previous);
+            previous = camera.view_z(CEIL);
+        }
+
+        assert_eq!(camera.view_z(CEIL), 24. + VIEW_HEIGHT);
+        assert!(!camera.is_moving());
+    }
+
+    #[test]
+    fn fall() {
+        let mut camera = Camera::new(128.);
+
+        camera.advance(0., CEIL, 0.1);
+        assert!(camera.z < 128. && camera.z > 0.);
+
+        camera.advance(0., CEIL, 2.);
+        assert_eq!(camera.z, 0.);
+        assert_eq!(camera.view_z(CEIL), VIEW_HEIGHT);
+    }
+
+    #[test]
+    fn crouch_and_fly() {
+        let mut camera = Camera::new(0.);
+
+        camera.set_mode(Mode::Crouch);
+        camera.advance(0., CEIL, 1.);
+        assert_eq!(camera.view_z(CEIL), VIEW_HEIGHT / 2.);
+
+        camera.set_mode(Mode::Fly);
+        camera.set_fly_direction(1.);
+        camera.advance(0., CEIL, 1.);
+        assert!(camera.z > 0.);
+
+        camera.set_fly_direction(0.);
+        let z = camera.z;
+        camera.advance(0., CEIL, 1.);
+        assert_eq!(camera.z, z);
+
+        // Flying is stopped by the ceiling
+        camera.set_fly_direction(1.);
+        camera.advance(0., CEIL, 10.);
+        assert_eq!(camera.z, CEIL - PLAYER_HEIGHT);
+
+        // The view is kept below the ceiling
+        camera.eye_height = 60.;
+        assert_eq!(camera.view_z(CEIL), CEIL - CEILING_CLEARANCE);
+    }
+}
@@ -0,0 +1,59 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The data could not be parsed as a WAD file
+    InvalidWad(String),
+
+    /// A lump required for rendering is not in the WAD
+    MissingLump(String),
+
+    /// The map is missing from the WAD or its data is inconsistent
+    BadMap(String, String),
+
+    /// A texture referenced by the map is not in the texture directory
+    MissingTexture(String),
+
+    /// The camera is not inside any sector of the map
+    CameraOutOfBounds,
+
+    /// The arguments to a call are inconsistent, such as a frame buffer that
+    /// does not match the given size
+    InvalidInput(String),
+}
+
+impl Error {
+    /// Numeric code for reporting errors across the wasm boundary. 0 is
+    /// reserved for success.
+    pub fn code(&self) -> i32 {
+        match self {
+            Error::InvalidWad(_) => 1,
+            Error::MissingLump(_) => 2,
+            Error::BadMap(_, _) => 3,
+            Error::MissingTexture(_) => 4,
+            Error::CameraOutOfBounds => 5,
+            Error::InvalidInput(_) => 6,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidWad(reason) => write!(f, "Invalid WAD: {}", reason),
+            Error::MissingLump(name) => write!(f, "Missing lump: {}", name),
+            Error::BadMap(map, reason) => write!(f, "Bad map {}: {}", map, reason),
+            Error::MissingTexture(name) => write!(f, "Missing texture: {}", name),
+            Error::CameraOutOfBounds => write!(f, "Camera is outside of the map"),
+            Error::InvalidInput(reason) => write!(f, "Invalid input: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Printable form of a zero padded lump or texture name
+pub fn name_str(name: &[u8]) -> String {
+    let len = name.iter().position(|&x| x == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
@@ -0,0 +1,129 @@
+//! The player's weapon drawn over the view, as vanilla's `R_DrawPSprite`
+
+use crate::rendering_state::VANILLA_PIXEL_ASPECT;
+use crate::util::*;
+use crate::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use cgmath::{vec2, Vector2};
+use ndarray::prelude::*;
+use wad_gfx::Sprite;
+
+const TAU: f32 = 2. * ::std::f32::consts::PI;
+
+// Resting position of the weapon in vanilla screen coordinates
+const WEAPON_X: f32 = 1.;
+const WEAPON_TOP: f32 = 32.;
+
+const MAX_BOB: f32 = 16.;
+
+// The weapon swings from side to side and back in this many tics
+const BOB_PERIOD: u32 = 64;
+
+/// Where the weapon is at the given tic, in vanilla screen coordinates, for
+/// a player moving at `speed` map units per tic. As in `A_WeaponReady`, the
+/// weapon swings sideways and dips twice per swing, more the faster the
+/// player moves.
+pub fn weapon_position(speed: f32, tic: u32) -> Vector2<f32> {
+    let bob = (speed * speed / 4.).min(MAX_BOB);
+    let angle = (tic % BOB_PERIOD) as f32 / BOB_PERIOD as f32 * TAU;
+
+    vec2(
+        WEAPON_X + bob * angle.cos(),
+        WEAPON_TOP + bob * angle.sin().abs(),
+    )
+}
+
+/// Draw a weapon sprite with its origin at `pos` in vanilla screen
+/// coordinates. `scale` is the height of a vanilla pixel in the view, and
+/// the sprite keeps its shape on pixels `pixel_aspect` times taller than
+/// wide. Like vanilla, the weapon is placed relative to the center of the
+/// view.
+pub fn draw_weapon(
+    view: &mut ArrayViewMut2<u8>,
+    sprite: &Sprite,
+    pos: Vector2<f32>,
+    scale: f32,
+    pixel_aspect: f32,
+) {
+    let (height, width) = view.dim();
+    let y_scale = scale;
+    let x_scale = scale * pixel_aspect / VANILLA_PIXEL_ASPECT;
+
+    let (top_offset, left_offset) = sprite.origin();
+    let left =
+        width as f32 / 2. + (pos.x - left_offset as f32 - SCREEN_WIDTH as f32 / 2.) * x_scale;
+    let top =
+        height as f32 / 2. + (pos.y - top_offset as f32 - SCREEN_HEIGHT as f32 / 2.) * y_scale;
+    let right = left + sprite.width() as f32 * x_scale;
+
+    let x_range = intersect(left.round() as i32..right.round() as i32, 0..width as i32);
+
+    for x in x_range {
+        let column = ((x as f32 + 0.5 - left) / x_scale) as u32;
+        let column = std::cmp::min(column, sprite.width() as u32 - 1);
+
+        put_scaled_column(
+            view,
+            x as usize,
+            top,
+            y_scale,
+            sprite,
+            column,
+            0..height as i32,
+            |pixel| pixel,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::*;
+
+    #[test]
+    fn bob() {
+        assert_eq!(weapon_position(0., 10), vec2(WEAPON_X, WEAPON_TOP));
+
+        // Bobbing is limited, and follows the tic
+        assert_eq!(weapon_position(100., 0), vec2(WEAPON_X + 16., WEAPON_TOP));
+        let quarter = weapon_position(100., BOB_PERIOD / 4);
+        assert!((quarter.x - WEAPON_X).abs() < 0.001);
+        assert!((quarter.y - WEAPON_TOP - 16.).abs() < 0.001);
+
+        // Walking at 4 units per tic bobs by 4 units
+        let half = weapon_position(4., BOB_PERIOD / 2);
+        assert!((half.x - (WEAPON_X - 4.)).abs() < 0.001);
+        assert!((half.y - WEAPON_TOP).abs() < 0.001);
+    }
+
+    #[test]
+    fn scaling() {
+        let lump = patch(10, 20, 7);
+        let sprite = Sprite::new(&lump);
+
+        let mut buf = vec![0; 320 * 200];
+        let mut view = ArrayViewMut2::from_shape((200, 320), &mut buf[..]).unwrap();
+        draw_weapon(&mut view, &sprite, vec2(1., 32.), 1., VANILLA_PIXEL_ASPECT);
+        assert_eq!(view[[32, 1]], 7);
+        assert_eq!(view[[51, 10]], 7);
+        assert_eq!(view[[52, 10]], 0);
+        assert_eq!(view[[32, 11]], 0);
+
+        // Twice the size at 640x400, and narrower on square pixels of the
+        // same height
+        let mut buf = vec![0; 640 * 400];
+        let mut view = ArrayViewMut2::from_shape((400, 640), &mut buf[..]).unwrap();
+        draw_weapon(&mut view, &sprite, vec2(1., 32.), 2., VANILLA_PIXEL_ASPECT);
+        assert_eq!(view[[64, 2]], 7);
+        assert_eq!(view[[103, 21]], 7);
+        assert_eq!(view[[104, 21]], 0);
+        assert_eq!(view[[64, 22]], 0);
+
+        let mut buf = vec![0; 533 * 400];
+        let mut view = ArrayViewMut2::from_shape((400, 533), &mut buf[..]).unwrap();
+        draw_weapon(&mut view, &sprite, vec2(160., 100.), 2., 1.);
+        // 10 columns scaled by 2 / 1.2, starting at the center
+        assert_eq!(view[[200, 267]], 7);
+        assert_eq!(view[[200, 282]], 7);
+        assert_eq!(view[[200, 284]], 0);
+    }
+}
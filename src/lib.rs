@@ -1,23 +1,105 @@
 extern crate wee_alloc;
 
 use cgmath::{vec2, Vector2};
-use std::{mem, slice};
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::{mem, ptr, slice};
 
+mod animation;
+mod blockmap;
 mod bsp_traverser;
-mod renderer;
+mod camera;
+pub mod error;
+mod hud;
+mod lighting;
+pub mod merge;
+mod movement;
+mod node_builder;
+pub mod palette;
+pub mod renderer;
 mod rendering_state;
+mod scrolling;
+pub mod status_bar;
+#[cfg(test)]
+mod test_support;
+mod things;
 mod util;
 
-const SCREEN_WIDTH: usize = 320;
-const SCREEN_HEIGHT: usize = 200;
+pub use rendering_state::{DEFAULT_FOV, MAX_FOV, MIN_FOV, VANILLA_PIXEL_ASPECT};
+
+/// The resolution of vanilla Doom
+pub const SCREEN_WIDTH: usize = 320;
+pub const SCREEN_HEIGHT: usize = 200;
 
 pub struct Input<'a> {
-    pal: &'a mut [u8; 768],
-    buf: &'a mut [u8; 320 * 200],
-    pos: Vector2<f32>,
-    dir: Vector2<f32>,
+    pub pal: &'a mut [u8; 768],
+    /// Palette indexes of `width * height` pixels, row by row
+    pub buf: &'a mut [u8],
+    pub width: usize,
+    pub height: usize,
+
+    /// How many times taller than wide the pixels are when displayed. This
+    /// is `VANILLA_PIXEL_ASPECT` for 320x200 shown at 4:3, and 1 for square
+    /// pixels.
+    pub pixel_aspect: f32,
+
+    pub pos: Vector2<f32>,
+    pub dir: Vector2<f32>,
+
+    /// Height of the view. `None` puts the view at eye height above the
+    /// floor under `pos`.
+    pub view_z: Option<f32>,
+
+    /// Vertical look angle in radians, positive when looking up. It is
+    /// applied by shearing the view, and limited to `MAX_PITCH`.
+    pub pitch: f32,
+
+    /// Horizontal field of view in radians, between `MIN_FOV` and `MAX_FOV`
+    pub fov: f32,
+
+    /// Which of the PLAYPAL palettes to write to `pal`, from 0 to 13
+    pub palette: usize,
+
+    /// The game tic, 35 per second, which animated textures and flats follow
+    pub tic: u32,
+
+    /// Name of the weapon sprite lump drawn over the view, such as PISGA0,
+    /// or `None` for no weapon
+    pub weapon: Option<&'a str>,
+
+    /// Horizontal speed of the player in map units per tic, which makes the
+    /// weapon bob
+    pub speed: f32,
+
+    /// The status bar to draw at the bottom of the screen, leaving the view
+    /// above it, or `None` to use the whole screen for the view
+    pub status_bar: Option<status_bar::StatusBar>,
 }
 
+use error::Error;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<Error>> = RefCell::new(None);
+}
+
+fn report<T>(result: Result<T, Error>) -> Option<T> {
+    match result {
+        Ok(x) => Some(x),
+        Err(err) => {
+            LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(err));
+            None
+        }
+    }
+}
+
+fn report_code(result: Result<(), Error>) -> i32 {
+    match report(result) {
+        Some(()) => 0,
+        None => last_error_code(),
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
@@ -27,72 +109,215 @@ pub fn alloc(size: usize) -> *mut u8 {
     Box::leak(block).as_mut_ptr()
 }
 
+#[no_mangle]
+pub unsafe fn dealloc(ptr: *mut u8, size: usize) {
+    Box::from_raw(slice::from_raw_parts_mut(ptr, size));
+}
+
+unsafe fn str_arg(ptr: *const u8, len: usize) -> String {
+    String::from_utf8_lossy(slice::from_raw_parts(ptr, len)).into_owned()
+}
+
+const STATUS_BAR_VALUES: usize = 14;
+
+/// Read a status bar from 14 little endian i32 values: health, armor, ammo of
+/// the weapon in hand (negative for none), the face code, the keys and
+/// weapons owned as bitmasks, then the current and maximum count of each ammo
+/// type. The values need not be aligned. Null means no status bar.
+unsafe fn status_bar_arg(ptr: *const u8) -> Option<status_bar::StatusBar> {
+    if ptr.is_null() {
+        return None;
+    }
+
+    let bytes = slice::from_raw_parts(ptr, STATUS_BAR_VALUES * 4);
+    let mut values = [0; STATUS_BAR_VALUES];
+    for (value, bytes) in values.iter_mut().zip(bytes.chunks_exact(4)) {
+        *value = i32::from_le_bytes(bytes.try_into().unwrap());
+    }
+    let bits = |mask: i32| {
+        let mut bits = [false; 6];
+        for (i, bit) in bits.iter_mut().enumerate() {
+            *bit = mask & (1 << i) != 0;
+        }
+        bits
+    };
+
+    let mut ammo_counts = [(0, 0); 4];
+    for (count, pair) in ammo_counts.iter_mut().zip(values[6..].chunks_exact(2)) {
+        *count = (pair[0], pair[1]);
+    }
+
+    Some(status_bar::StatusBar {
+        health: values[0],
+        armor: values[1],
+        ammo: if values[2] < 0 { None } else { Some(values[2]) },
+        face: status_bar::Face::from_code(values[3]).unwrap_or(status_bar::Face::Straight(0)),
+        keys: bits(values[4]),
+        arms: bits(values[5]),
+        ammo_counts,
+    })
+}
+
+/// Error code of the most recent failed call, or 0 if nothing has failed
+#[no_mangle]
+pub fn last_error_code() -> i32 {
+    LAST_ERROR.with(|last_error| last_error.borrow().as_ref().map_or(0, Error::code))
+}
+
+/// Message for the most recent failed call, or null if nothing has failed
+#[no_mangle]
+pub fn last_error_message() -> *const String {
+    LAST_ERROR.with(|last_error| match last_error.borrow().as_ref() {
+        Some(err) => Box::leak(Box::new(err.to_string())) as _,
+        None => ptr::null(),
+    })
+}
+
 #[no_mangle]
 pub fn parse_wad(wad: *mut u8, wad_sz: usize) -> *mut wad::Wad {
     let wad_slice: &mut [u8] = unsafe { slice::from_raw_parts_mut(mem::transmute(wad), wad_sz) };
 
-    let wad = Box::new(wad::parse_wad(Vec::from(wad_slice)).unwrap());
+    let wad = merge::parse_wads(wad_slice, &[]);
+
+    match report(wad) {
+        Some(wad) => Box::leak(Box::new(wad)) as _,
+        None => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub fn wad_list_new() -> *mut Vec<Vec<u8>> {
+    Box::leak(Box::new(vec![])) as _
+}
 
-    Box::leak(wad) as _
+/// Add a WAD to the list. The first WAD is the IWAD, and the following are
+/// PWADs loaded on top of it in order.
+#[no_mangle]
+pub fn wad_list_push(list: *mut Vec<Vec<u8>>, wad: *mut u8, wad_sz: usize) {
+    let wad_slice: &[u8] = unsafe { slice::from_raw_parts(wad, wad_sz) };
+    unsafe { (*list).push(Vec::from(wad_slice)) };
 }
 
+/// Consumes the list
 #[no_mangle]
-pub fn init<'a>(wad: *mut wad::Wad) -> *mut renderer::State<'a> {
+pub fn parse_wad_list(list: *mut Vec<Vec<u8>>) -> *mut wad::Wad {
+    let list = unsafe { Box::from_raw(list) };
+
+    let wad = match list.split_first() {
+        Some((iwad, pwads)) => merge::parse_wads(iwad, pwads),
+        None => Err(Error::InvalidWad("No IWAD given".to_owned())),
+    };
+
+    match report(wad) {
+        Some(wad) => Box::leak(Box::new(wad)) as _,
+        None => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub fn map_names(wad: *mut wad::Wad) -> *const String {
+    let wad: &wad::Wad = unsafe { &*wad };
+
+    let names = Box::new(util::map_names(&wad.as_slice()).join("\n"));
+    Box::leak(names) as _
+}
+
+#[no_mangle]
+pub fn init<'a>(
+    wad: *mut wad::Wad,
+    map_name: *const u8,
+    map_name_len: usize,
+) -> *mut renderer::State<'a> {
     let wad: &'a wad::Wad = unsafe { &*wad };
+    let map_name = unsafe { str_arg(map_name, map_name_len) };
 
-    let state = Box::new(renderer::State::new(wad));
+    match report(renderer::State::new(wad, &map_name)) {
+        Some(state) => Box::leak(Box::new(state)) as _,
+        None => ptr::null_mut(),
+    }
+}
 
-    Box::leak(state) as _
+#[no_mangle]
+pub fn set_map(state: *mut renderer::State, map_name: *const u8, map_name_len: usize) -> i32 {
+    let map_name = unsafe { str_arg(map_name, map_name_len) };
+    report_code(unsafe { (*state).set_map(&map_name) })
 }
 
 #[no_mangle]
 pub fn render(
     state: *mut renderer::State,
     screen_ptr: *mut u8,
+    width: usize,
+    height: usize,
+    pixel_aspect: f32,
     cx: f32,
     cy: f32,
     dx: f32,
     dy: f32,
-) {
+    cz: f32,
+    pitch: f32,
+    fov: f32,
+    palette: usize,
+    gamma: usize,
+    tic: u32,
+    weapon: *const u8,
+    weapon_len: usize,
+    speed: f32,
+    status_bar: *const u8,
+) -> i32 {
     let mut state = unsafe { Box::from_raw(state) };
 
-    let screen_slice: &mut [u8] = unsafe {
-        slice::from_raw_parts_mut(mem::transmute(screen_ptr), SCREEN_WIDTH * SCREEN_HEIGHT * 4)
-    };
+    let screen_slice: &mut [u8] =
+        unsafe { slice::from_raw_parts_mut(mem::transmute(screen_ptr), width * height * 4) };
 
     let mut pal = [0; 768];
-    for i in 0..256 {
-        pal[i * 3 + 0] = i as u8;
-        pal[i * 3 + 1] = 0;
-        pal[i * 3 + 2] = 0;
-    }
+    let gamma = palette::gamma_table(gamma);
 
-    let mut framebuf = [0; SCREEN_WIDTH * SCREEN_HEIGHT];
+    let mut framebuf = vec![0; width * height];
+
+    // An empty name hides the weapon
+    let weapon = unsafe { str_arg(weapon, weapon_len) };
 
     let input = Input {
         pal: &mut pal,
         buf: &mut framebuf,
+        width,
+        height,
+        pixel_aspect,
         pos: vec2(cx, cy),
         dir: vec2(dx, dy),
+        view_z: if cz.is_nan() { None } else { Some(cz) },
+        pitch,
+        fov,
+        palette,
+        tic,
+        weapon: if weapon.is_empty() {
+            None
+        } else {
+            Some(weapon.as_str())
+        },
+        speed,
+        status_bar: unsafe { status_bar_arg(status_bar) },
     };
 
-    state.render(input);
+    let result = state.render(input);
 
     for (dst, src) in screen_slice.chunks_exact_mut(4).zip(framebuf.iter_mut()) {
         let col = *src as usize;
-        dst[0] = pal[col * 3 + 0];
-        dst[1] = pal[col * 3 + 1];
-        dst[2] = pal[col * 3 + 2];
+        dst[0] = gamma[pal[col * 3 + 0] as usize];
+        dst[1] = gamma[pal[col * 3 + 1] as usize];
+        dst[2] = gamma[pal[col * 3 + 2] as usize];
         dst[3] = 255;
     }
 
     Box::leak(state);
+
+    report_code(result)
 }
 
 #[no_mangle]
 pub unsafe fn str_buf(s: *mut String) -> *const u8 {
-    let buf = (*s).as_bytes();
-    &buf[0] as _
+    (*s).as_bytes().as_ptr()
 }
 
 #[no_mangle]
@@ -111,22 +336,97 @@ pub fn svg_from_map(state: *mut renderer::State) -> *const String {
     Box::leak(svg) as _
 }
 
+/// Move the player by (mx, my) from (cx, cy), colliding with the map. The
+/// resolved x, y and floor height are written to `out` as three f32 values.
+#[no_mangle]
+pub fn move_player(
+    state: *mut renderer::State,
+    cx: f32,
+    cy: f32,
+    mx: f32,
+    my: f32,
+    radius: f32,
+    out: *mut u8,
+) -> i32 {
+    let result = unsafe { (*state).move_player(vec2(cx, cy), vec2(mx, my), radius) };
+
+    report_code(result.map(|(pos, floor)| {
+        let out = out as *mut f32;
+        unsafe {
+            out.write_unaligned(pos.x);
+            out.add(1).write_unaligned(pos.y);
+            out.add(2).write_unaligned(floor);
+        }
+    }))
+}
+
+/// The horizontal field of view of the last rendered frame, in radians
+#[no_mangle]
+pub fn fov(state: *mut renderer::State) -> f32 {
+    unsafe { (*state).fov() }
+}
+
+/// Put the camera on the floor at (cx, cy)
+#[no_mangle]
+pub fn camera_reset(state: *mut renderer::State, cx: f32, cy: f32) -> i32 {
+    report_code(unsafe { (*state).reset_camera(vec2(cx, cy)) })
+}
+
+/// Advance the camera at (cx, cy) by the given time, returning the height of
+/// the view, or NaN if the position is outside the map
+#[no_mangle]
+pub fn camera_update(state: *mut renderer::State, cx: f32, cy: f32, seconds: f32) -> f32 {
+    report(unsafe { (*state).update_camera(vec2(cx, cy), seconds) }).unwrap_or(std::f32::NAN)
+}
+
+/// Whether the camera is still moving vertically, 1 for true and 0 for false
+#[no_mangle]
+pub fn camera_is_moving(state: *mut renderer::State) -> i32 {
+    unsafe { (*state).camera().is_moving() as i32 }
+}
+
+/// Set the camera mode: 0 to walk, 1 to crouch, 2 to fly. Other values are
+/// ignored.
+#[no_mangle]
+pub fn camera_set_mode(state: *mut renderer::State, mode: i32) {
+    if let Some(mode) = camera::Mode::from_code(mode) {
+        unsafe { (*state).camera_mut().set_mode(mode) };
+    }
+}
+
+#[no_mangle]
+pub fn camera_set_eye_height(state: *mut renderer::State, eye_height: f32) {
+    unsafe { (*state).camera_mut().eye_height = eye_height };
+}
+
+/// Fly up for a positive direction and down for a negative one
+#[no_mangle]
+pub fn camera_set_fly_direction(state: *mut renderer::State, direction: f32) {
+    unsafe { (*state).camera_mut().set_fly_direction(direction) };
+}
+
+/// The spawn point, or NaN if the map has no player 1 start
+fn spawn_point(state: *mut renderer::State) -> (Vector2<f32>, Vector2<f32>) {
+    let nan = vec2(std::f32::NAN, std::f32::NAN);
+    report(unsafe { (*state).spawn_point() }).unwrap_or((nan, nan))
+}
+
 #[no_mangle]
 pub fn spawn_point_x(state: *mut renderer::State) -> f32 {
-    unsafe { (*state).spawn_point().0.x }
+    spawn_point(state).0.x
 }
 
 #[no_mangle]
 pub fn spawn_point_y(state: *mut renderer::State) -> f32 {
-    unsafe { (*state).spawn_point().0.y }
+    spawn_point(state).0.y
 }
 
 #[no_mangle]
 pub fn spawn_point_dx(state: *mut renderer::State) -> f32 {
-    unsafe { (*state).spawn_point().1.x }
+    spawn_point(state).1.x
 }
 
 #[no_mangle]
 pub fn spawn_point_dy(state: *mut renderer::State) -> f32 {
-    unsafe { (*state).spawn_point().1.y }
+    spawn_point(state).1.y
 }
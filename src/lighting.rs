@@ -0,0 +1,62 @@
+use cgmath::Vector2;
+
+pub const COLORMAP_SIZE: usize = 256;
+
+const LIGHT_LEVELS: i32 = 16;
+const LIGHT_SEG_SHIFT: u8 = 4;
+pub const NUM_COLORMAPS: i32 = 32;
+const MAX_LIGHT_SCALE: i32 = 48;
+const MAX_LIGHT_Z: i32 = 128;
+const DIST_MAP: i32 = 2;
+
+// The vanilla tables are computed for a 320 pixel wide view with a
+// projection plane this far away
+const VANILLA_PROJECTION: f32 = 160.;
+
+fn clamp_level(light_level: i32) -> i32 {
+    light_level.max(0).min(LIGHT_LEVELS - 1)
+}
+
+fn start_map(light_level: i32) -> i32 {
+    (LIGHT_LEVELS - 1 - clamp_level(light_level)) * 2 * NUM_COLORMAPS / LIGHT_LEVELS
+}
+
+fn clamp_map(level: i32) -> usize {
+    level.max(0).min(NUM_COLORMAPS - 1) as usize
+}
+
+pub fn sector_light_level(sector_light: u8) -> i32 {
+    (sector_light >> LIGHT_SEG_SHIFT) as i32
+}
+
+/// Light level for a wall in the given sector, including vanilla's fake
+/// contrast for walls aligned with the map axes
+pub fn wall_light_level(sector_light: u8, a: Vector2<f32>, b: Vector2<f32>) -> i32 {
+    let light_level = sector_light_level(sector_light);
+
+    if a.y == b.y {
+        light_level - 1
+    } else if a.x == b.x {
+        light_level + 1
+    } else {
+        light_level
+    }
+}
+
+/// Colormap index for a wall column at view depth `z`, as vanilla's `scalelight`
+pub fn wall_colormap(light_level: i32, z: f32) -> usize {
+    // Vanilla indexes by projected scale in 4.12 fixed point
+    let scale = VANILLA_PROJECTION / z;
+    let index = ((scale * 16.) as i32).min(MAX_LIGHT_SCALE - 1);
+
+    clamp_map(start_map(light_level) - index / DIST_MAP)
+}
+
+/// Colormap index for a floor or ceiling pixel at view depth `z`, as
+/// vanilla's `zlight`
+pub fn plane_colormap(light_level: i32, z: f32) -> usize {
+    let index = ((z / 16.) as i32).max(0).min(MAX_LIGHT_Z - 1);
+    let scale = VANILLA_PROJECTION as i32 / (index + 1);
+
+    clamp_map(start_map(light_level) - scale / DIST_MAP)
+}
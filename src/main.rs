@@ -0,0 +1,284 @@
+use cgmath::{vec2, Vector2};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use structopt::StructOpt;
+use wad_render::status_bar::{Face, StatusBar};
+use wad_render::{
+    merge, palette, renderer, Input, SCREEN_HEIGHT, SCREEN_WIDTH, VANILLA_PIXEL_ASPECT,
+};
+
+const TAU: f32 = 2. * ::std::f32::consts::PI;
+
+enum Camera {
+    Spawn,
+    At(Vector2<f32>, f32),
+}
+
+impl FromStr for Camera {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Camera, String> {
+        if s == "spawn" {
+            return Ok(Camera::Spawn);
+        }
+
+        let parts = s
+            .split(',')
+            .map(|x| x.trim().parse::<f32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| err.to_string())?;
+
+        match parts[..] {
+            [x, y, angle] => Ok(Camera::At(vec2(x, y), angle)),
+            _ => Err("Expected x,y,angle or spawn".to_owned()),
+        }
+    }
+}
+
+struct Size {
+    width: usize,
+    height: usize,
+}
+
+impl FromStr for Size {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Size, String> {
+        let parts = s
+            .split('x')
+            .map(|x| x.trim().parse::<usize>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| err.to_string())?;
+
+        match parts[..] {
+            [width, height] if width > 0 && height > 0 => Ok(Size { width, height }),
+            _ => Err("Expected WIDTHxHEIGHT".to_owned()),
+        }
+    }
+}
+
+const KEY_NAMES: [&str; 6] = [
+    "blue",
+    "yellow",
+    "red",
+    "blue-skull",
+    "yellow-skull",
+    "red-skull",
+];
+
+struct Keys([bool; 6]);
+
+impl FromStr for Keys {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Keys, String> {
+        let mut keys = [false; 6];
+
+        for name in s.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            match KEY_NAMES.iter().position(|&x| x == name) {
+                Some(i) => keys[i] = true,
+                None => return Err(format!("Expected keys from {}", KEY_NAMES.join(", "))),
+            }
+        }
+
+        Ok(Keys(keys))
+    }
+}
+
+#[derive(StructOpt)]
+#[structopt(name = "wad-render", about = "Render a view of a Doom map to an image")]
+struct Opt {
+    /// The IWAD to load
+    #[structopt(parse(from_os_str))]
+    iwad: PathBuf,
+
+    /// Name of the map, such as E1M1 or MAP01
+    map: String,
+
+    /// Output file. The format is PPM if the extension is .ppm, PNG otherwise
+    #[structopt(parse(from_os_str))]
+    output: PathBuf,
+
+    /// Camera position and angle in degrees as x,y,angle, or "spawn" for the
+    /// player 1 start
+    #[structopt(
+        long = "camera",
+        default_value = "spawn",
+        raw(allow_hyphen_values = "true")
+    )]
+    camera: Camera,
+
+    /// Vertical look angle in degrees, positive when looking up
+    #[structopt(long = "pitch", default_value = "0", raw(allow_hyphen_values = "true"))]
+    pitch: f32,
+
+    /// Horizontal field of view in degrees, up to 170
+    #[structopt(long = "fov", default_value = "90")]
+    fov: f32,
+
+    /// Size of the output image as WIDTHxHEIGHT
+    #[structopt(long = "size", default_value = "320x200")]
+    size: Size,
+
+    /// How many times taller than wide the pixels are meant to be displayed.
+    /// Defaults to 1.2 for 16:10 sizes like 320x200, which are shown at 4:3
+    /// like vanilla, and 1 otherwise.
+    #[structopt(long = "pixel-aspect")]
+    pixel_aspect: Option<f32>,
+
+    /// The PLAYPAL palette to use, from 0 to 13. 1 to 8 are the red damage
+    /// palettes, 9 to 12 the pickup palettes and 13 the radiation suit.
+    #[structopt(long = "palette", default_value = "0")]
+    palette: usize,
+
+    /// Gamma correction level, from 0 to 4 as in vanilla
+    #[structopt(long = "gamma", default_value = "0")]
+    gamma: usize,
+
+    /// Game tic to show animated textures and flats at, 35 per second
+    #[structopt(long = "tic", default_value = "0")]
+    tic: u32,
+
+    /// Weapon sprite to draw over the view, such as PISGA0 or SHTGA0
+    #[structopt(long = "weapon", default_value = "PISGA0")]
+    weapon: String,
+
+    /// Leave the weapon out of the image
+    #[structopt(long = "no-weapon")]
+    no_weapon: bool,
+
+    /// Speed of the player in map units per tic, which makes the weapon bob
+    #[structopt(long = "speed", default_value = "0")]
+    speed: f32,
+
+    /// Draw the status bar below the view
+    #[structopt(long = "status-bar")]
+    status_bar: bool,
+
+    /// Health shown on the status bar, which also makes the face look hurt
+    #[structopt(
+        long = "health",
+        default_value = "100",
+        raw(allow_hyphen_values = "true")
+    )]
+    health: i32,
+
+    /// Armor shown on the status bar
+    #[structopt(long = "armor", default_value = "0", raw(allow_hyphen_values = "true"))]
+    armor: i32,
+
+    /// Bullets shown on the status bar, as the ammo of the weapon in hand
+    #[structopt(long = "ammo", default_value = "50", raw(allow_hyphen_values = "true"))]
+    ammo: i32,
+
+    /// Face on the status bar: straight, left, right, ouch, evil, rampage,
+    /// god or dead
+    #[structopt(long = "face", default_value = "straight")]
+    face: Face,
+
+    /// Keys shown on the status bar as a comma separated list of blue,
+    /// yellow, red, blue-skull, yellow-skull and red-skull
+    #[structopt(long = "keys", default_value = "")]
+    keys: Keys,
+
+    /// PWADs to load on top of the IWAD, in order
+    #[structopt(long = "pwad", parse(from_os_str))]
+    pwads: Vec<PathBuf>,
+}
+
+fn write_ppm(path: &Path, size: &Size, rgb: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write!(out, "P6\n{} {}\n255\n", size.width, size.height)?;
+    out.write_all(rgb)?;
+    Ok(())
+}
+
+fn write_png(path: &Path, size: &Size, rgb: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let out = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(out, size.width as u32, size.height as u32);
+    encoder.set(png::ColorType::RGB).set(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(rgb)?;
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let opt = Opt::from_args();
+
+    let iwad = fs::read(&opt.iwad)?;
+    let pwads = opt
+        .pwads
+        .iter()
+        .map(fs::read)
+        .collect::<Result<Vec<_>, _>>()?;
+    let wad = merge::parse_wads(&iwad, &pwads)?;
+
+    let mut state = renderer::State::new(&wad, &opt.map)?;
+
+    let (pos, dir) = match opt.camera {
+        Camera::Spawn => state.spawn_point()?,
+        Camera::At(pos, angle) => {
+            let angle = angle / 360. * TAU;
+            (pos, vec2(angle.cos(), angle.sin()))
+        }
+    };
+
+    let size = &opt.size;
+    let pixel_aspect = opt.pixel_aspect.unwrap_or(
+        if size.width * SCREEN_HEIGHT == size.height * SCREEN_WIDTH {
+            VANILLA_PIXEL_ASPECT
+        } else {
+            1.
+        },
+    );
+
+    let mut pal = [0; 768];
+    let mut framebuf = vec![0; size.width * size.height];
+
+    state.render(Input {
+        pal: &mut pal,
+        buf: &mut framebuf,
+        width: size.width,
+        height: size.height,
+        pixel_aspect,
+        pos,
+        dir,
+        view_z: None,
+        pitch: opt.pitch / 360. * TAU,
+        fov: opt.fov / 360. * TAU,
+        palette: opt.palette,
+        tic: opt.tic,
+        weapon: if opt.no_weapon {
+            None
+        } else {
+            Some(opt.weapon.as_str())
+        },
+        speed: opt.speed,
+        status_bar: if opt.status_bar {
+            Some(StatusBar {
+                health: opt.health,
+                armor: opt.armor,
+                ammo: Some(opt.ammo),
+                arms: [true, false, false, false, false, false],
+                keys: opt.keys.0,
+                ammo_counts: [(opt.ammo, 200), (0, 50), (0, 50), (0, 300)],
+                face: opt.face,
+            })
+        } else {
+            None
+        },
+    })?;
+
+    let gamma = palette::gamma_table(opt.gamma);
+    let rgb = framebuf
+        .iter()
+        .flat_map(|&col| pal[col as usize * 3..col as usize * 3 + 3].iter())
+        .map(|&x| gamma[x as usize])
+        .collect::<Vec<_>>();
+
+    match opt.output.extension().and_then(|x| x.to_str()) {
+        Some("ppm") => write_ppm(&opt.output, size, &rgb),
+        _ => write_png(&opt.output, size, &rgb),
+    }
+}
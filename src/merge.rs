@@ -0,0 +1,473 @@
+use crate::error::{name_str, Error};
+use crate::node_builder::{build_nodes, nodes_are_valid, NodeLumps};
+use std::convert::TryInto;
+
+const HEADER_SIZE: usize = 12;
+const DIRECTORY_ENTRY_SIZE: usize = 16;
+
+// Lumps that follow a map marker and belong to that map
+const MAP_LUMPS: &[&[u8]] = &[
+    b"THINGS",
+    b"LINEDEFS",
+    b"SIDEDEFS",
+    b"VERTEXES",
+    b"SEGS",
+    b"SSECTORS",
+    b"NODES",
+    b"SECTORS",
+    b"REJECT",
+    b"BLOCKMAP",
+    b"BEHAVIOR",
+];
+
+// Marker namespaces whose contents are merged lump by lump. The IWAD uses the
+// first start marker, while PWADs may use either.
+const NAMESPACES: &[(&[&[u8]], &[&[u8]])] = &[
+    (&[b"F_START", b"FF_START"], &[b"F_END", b"FF_END"]),
+    (&[b"S_START", b"SS_START"], &[b"S_END", b"SS_END"]),
+    (&[b"P_START", b"PP_START"], &[b"P_END", b"PP_END"]),
+];
+
+#[derive(Clone)]
+struct Lump<'a> {
+    name: [u8; 8],
+    data: &'a [u8],
+}
+
+impl<'a> Lump<'a> {
+    fn is(&self, name: &[u8]) -> bool {
+        self.name.iter().take_while(|&&x| x != 0).eq(name.iter())
+    }
+
+    fn is_any(&self, names: &[&[u8]]) -> bool {
+        names.iter().any(|name| self.is(name))
+    }
+
+    fn marker(name: &[u8]) -> Lump<'static> {
+        let mut id = [0u8; 8];
+        id[..name.len()].copy_from_slice(name);
+        Lump {
+            name: id,
+            data: &[],
+        }
+    }
+}
+
+fn read_i32(buf: &[u8], offset: usize) -> Result<usize, Error> {
+    let bytes = buf
+        .get(offset..offset + 4)
+        .ok_or_else(|| Error::InvalidWad("Truncated file".to_owned()))?;
+    let value = i32::from_le_bytes(bytes.try_into().unwrap());
+    if value < 0 {
+        return Err(Error::InvalidWad("Negative offset or size".to_owned()));
+    }
+    Ok(value as usize)
+}
+
+fn read_directory(buf: &[u8]) -> Result<Vec<Lump>, Error> {
+    match buf.get(0..4) {
+        Some(b"IWAD") | Some(b"PWAD") => (),
+        _ => return Err(Error::InvalidWad("Missing IWAD/PWAD header".to_owned())),
+    }
+
+    let num_lumps = read_i32(buf, 4)?;
+    let directory = read_i32(buf, 8)?;
+
+    let truncated = || Error::InvalidWad("Truncated directory".to_owned());
+
+    // A directory that cannot fit in the file is rejected before anything is
+    // allocated for it, with sizes checked so they cannot overflow on wasm32
+    let directory_end = num_lumps
+        .checked_mul(DIRECTORY_ENTRY_SIZE)
+        .and_then(|size| size.checked_add(directory))
+        .ok_or_else(truncated)?;
+    if directory_end > buf.len() {
+        return Err(truncated());
+    }
+
+    (0..num_lumps)
+        .map(|i| {
+            let entry = directory + i * DIRECTORY_ENTRY_SIZE;
+
+            let pos = read_i32(buf, entry)?;
+            let size = read_i32(buf, entry + 4)?;
+            let name = buf.get(entry + 8..entry + 16).ok_or_else(truncated)?;
+            let data = pos
+                .checked_add(size)
+                .and_then(|end| buf.get(pos..end))
+                .ok_or_else(|| {
+                    Error::InvalidWad(format!("Lump {} is out of bounds", name_str(name)))
+                })?;
+
+            Ok(Lump {
+                name: name.try_into().unwrap(),
+                data,
+            })
+        })
+        .collect()
+}
+
+fn is_map_marker(lumps: &[Lump], index: usize) -> bool {
+    lumps
+        .get(index + 1)
+        .map_or(false, |next| next.is(b"THINGS"))
+}
+
+/// Index past the last lump of the map whose marker is at `marker`.
+/// `is_named` tells whether the lump at an index has the given name, and
+/// must be false past the end.
+pub fn map_end_by(marker: usize, is_named: impl Fn(usize, &[u8]) -> bool) -> usize {
+    let mut end = marker + 1;
+    while MAP_LUMPS.iter().any(|name| is_named(end, name)) {
+        end += 1;
+    }
+    end
+}
+
+fn map_end(lumps: &[Lump], marker: usize) -> usize {
+    map_end_by(marker, |i, name| {
+        lumps.get(i).map_or(false, |lump| lump.is(name))
+    })
+}
+
+fn find_namespace(lumps: &[Lump], start: &[&[u8]], end: &[&[u8]]) -> Option<(usize, usize)> {
+    let start = lumps.iter().position(|lump| lump.is_any(start))?;
+    let end = start + lumps[start..].iter().position(|lump| lump.is_any(end))?;
+    Some((start, end))
+}
+
+fn merge_map<'a>(merged: &mut Vec<Lump<'a>>, map: &[Lump<'a>]) {
+    let existing =
+        (0..merged.len()).find(|&i| merged[i].name == map[0].name && is_map_marker(merged, i));
+
+    match existing {
+        Some(marker) => {
+            let tail = merged.split_off(map_end(merged, marker));
+            merged.truncate(marker);
+            merged.extend(map.iter().cloned());
+            merged.extend(tail);
+        }
+        None => merged.extend(map.iter().cloned()),
+    }
+}
+
+fn merge_namespace<'a>(
+    merged: &mut Vec<Lump<'a>>,
+    (start, end): (&[&[u8]], &[&[u8]]),
+    lumps: &[Lump<'a>],
+) {
+    let (ns_start, mut ns_end) = match find_namespace(merged, start, end) {
+        Some(x) => x,
+        None => {
+            merged.push(Lump::marker(start[0]));
+            merged.push(Lump::marker(end[0]));
+            (merged.len() - 2, merged.len() - 1)
+        }
+    };
+
+    // Nested markers such as F1_START carry no data
+    for lump in lumps.iter().filter(|lump| !lump.data.is_empty()) {
+        let existing = (ns_start + 1..ns_end).find(|&i| merged[i].name == lump.name);
+
+        match existing {
+            Some(i) => merged[i] = lump.clone(),
+            None => {
+                merged.insert(ns_end, lump.clone());
+                ns_end += 1;
+            }
+        }
+    }
+}
+
+fn merge_lump<'a>(merged: &mut Vec<Lump<'a>>, lump: &Lump<'a>) {
+    let namespaces = NAMESPACES
+        .iter()
+        .filter_map(|&(start, end)| find_namespace(merged, start, end))
+        .collect::<Vec<_>>();
+
+    let is_in_namespace = |i: usize| {
+        namespaces
+            .iter()
+            .any(|&(ns_start, ns_end)| ns_start < i && i < ns_end)
+    };
+
+    let existing = (0..merged.len())
+        .rev()
+        .find(|&i| merged[i].name == lump.name && !is_in_namespace(i));
+
+    match existing {
+        Some(i) => merged[i] = lump.clone(),
+        None => merged.push(lump.clone()),
+    }
+}
+
+fn merge_pwad<'a>(merged: &mut Vec<Lump<'a>>, pwad: &[Lump<'a>]) {
+    let mut i = 0;
+    while i < pwad.len() {
+        if is_map_marker(pwad, i) {
+            let end = map_end(pwad, i);
+            merge_map(merged, &pwad[i..end]);
+            i = end;
+            continue;
+        }
+
+        let namespace = NAMESPACES.iter().find(|&&(start, _)| pwad[i].is_any(start));
+
+        if let Some(&(start, end)) = namespace {
+            let ns_end = pwad[i..]
+                .iter()
+                .position(|lump| lump.is_any(end))
+                .map_or(pwad.len(), |n| i + n);
+            merge_namespace(merged, (start, end), &pwad[i + 1..ns_end]);
+            i = ns_end + 1;
+            continue;
+        }
+
+        merge_lump(merged, &pwad[i]);
+        i += 1;
+    }
+}
+
+fn write_wad(lumps: &[Lump]) -> Vec<u8> {
+    let data_size: usize = lumps.iter().map(|lump| lump.data.len()).sum();
+    let directory = HEADER_SIZE + data_size;
+
+    let mut buf = Vec::with_capacity(directory + lumps.len() * DIRECTORY_ENTRY_SIZE);
+    buf.extend_from_slice(b"IWAD");
+    buf.extend_from_slice(&(lumps.len() as i32).to_le_bytes());
+    buf.extend_from_slice(&(directory as i32).to_le_bytes());
+
+    for lump in lumps {
+        buf.extend_from_slice(lump.data);
+    }
+
+    let mut pos = HEADER_SIZE;
+    for lump in lumps {
+        buf.extend_from_slice(&(pos as i32).to_le_bytes());
+        buf.extend_from_slice(&(lump.data.len() as i32).to_le_bytes());
+        buf.extend_from_slice(&lump.name);
+        pos += lump.data.len();
+    }
+
+    buf
+}
+
+fn map_lump<'l, 'a>(lumps: &'l [Lump<'a>], marker: usize, name: &[u8]) -> Option<&'l Lump<'a>> {
+    lumps[marker + 1..map_end(lumps, marker)]
+        .iter()
+        .find(|lump| lump.is(name))
+}
+
+/// Build nodes for every map whose node lumps are missing or invalid.
+/// Returns the map marker indexes with the new lumps.
+fn build_missing_nodes(lumps: &[Lump]) -> Vec<(usize, NodeLumps)> {
+    (0..lumps.len())
+        .filter(|&i| is_map_marker(lumps, i))
+        .filter_map(|marker| {
+            let data =
+                |name: &[u8]| map_lump(lumps, marker, name).map_or(&[][..], |lump| lump.data);
+            let (vertexes, linedefs) = (data(b"VERTEXES"), data(b"LINEDEFS"));
+
+            if nodes_are_valid(
+                vertexes,
+                linedefs,
+                data(b"SEGS"),
+                data(b"SSECTORS"),
+                data(b"NODES"),
+            ) {
+                return None;
+            }
+
+            build_nodes(vertexes, linedefs).map(|nodes| (marker, nodes))
+        })
+        .collect()
+}
+
+fn replace_nodes<'a>(lumps: &mut Vec<Lump<'a>>, marker: usize, nodes: &'a NodeLumps) {
+    let end = map_end(lumps, marker);
+    let tail = lumps.split_off(end);
+    let map = lumps.split_off(marker + 1);
+
+    // Rebuild the map in the canonical lump order, which also places node
+    // lumps that were missing altogether
+    for &name in MAP_LUMPS {
+        let built = match name {
+            b"VERTEXES" => Some(&nodes.vertexes[..]),
+            b"SEGS" => Some(&nodes.segs[..]),
+            b"SSECTORS" => Some(&nodes.subsectors[..]),
+            b"NODES" => Some(&nodes.nodes[..]),
+            _ => None,
+        };
+
+        match built {
+            Some(data) => lumps.push(Lump {
+                data,
+                ..Lump::marker(name)
+            }),
+            None => lumps.extend(map.iter().find(|lump| lump.is(name)).cloned()),
+        }
+    }
+
+    lumps.extend(tail);
+}
+
+/// Combine an IWAD with any number of PWADs into a single WAD, following
+/// the override rules of Doom source ports: later lumps replace earlier lumps
+/// of the same name, maps replace whole maps, and flats, sprites and patches
+/// between markers are merged into the corresponding IWAD sections.
+///
+/// Maps with missing or broken nodes get freshly built ones.
+pub fn merge_wads(iwad: &[u8], pwads: &[Vec<u8>]) -> Result<Vec<u8>, Error> {
+    let mut merged = read_directory(iwad)?;
+
+    for pwad in pwads {
+        merge_pwad(&mut merged, &read_directory(pwad)?);
+    }
+
+    // Later markers first, so earlier marker indexes stay valid
+    let built = build_missing_nodes(&merged);
+    for (marker, nodes) in built.iter().rev() {
+        replace_nodes(&mut merged, *marker, nodes);
+    }
+
+    Ok(write_wad(&merged))
+}
+
+/// Parse an IWAD with PWADs loaded on top of it
+pub fn parse_wads(iwad: &[u8], pwads: &[Vec<u8>]) -> Result<wad::Wad, Error> {
+    let merged = merge_wads(iwad, pwads)?;
+    wad::parse_wad(merged).map_err(|err| Error::InvalidWad(format!("{:?}", err)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::*;
+
+    fn lump<'a>(name: &[u8], data: &'a [u8]) -> Lump<'a> {
+        Lump {
+            data,
+            ..Lump::marker(name)
+        }
+    }
+
+    fn room_lump(map: &MapData, name: &[u8; 8]) -> Vec<u8> {
+        let wad = map.add_to(WadBuilder::new(), "E1M1").parse();
+        let wad = wad.as_slice();
+        wad.by_id(name).unwrap().to_vec()
+    }
+
+    fn names(buf: &[u8]) -> Vec<String> {
+        read_directory(buf)
+            .unwrap()
+            .iter()
+            .map(|lump| name_str(&lump.name))
+            .collect()
+    }
+
+    #[test]
+    fn merge() {
+        let iwad = write_wad(&[
+            lump(b"PLAYPAL", b"iwad"),
+            lump(b"E1M1", b""),
+            lump(b"THINGS", b"iwad"),
+            lump(b"LINEDEFS", b"iwad"),
+            lump(b"F_START", b""),
+            lump(b"FLOOR1", b"iwad"),
+            lump(b"F_END", b""),
+        ]);
+        let pwad = write_wad(&[
+            lump(b"E1M1", b""),
+            lump(b"THINGS", b"pwad"),
+            lump(b"FF_START", b""),
+            lump(b"FLOOR2", b"pwad"),
+            lump(b"FF_END", b""),
+            lump(b"PLAYPAL", b"pwad"),
+        ]);
+
+        let merged = merge_wads(&iwad, &[pwad]).unwrap();
+
+        assert_eq!(
+            names(&merged),
+            ["PLAYPAL", "E1M1", "THINGS", "F_START", "FLOOR1", "FLOOR2", "F_END"]
+        );
+
+        let lumps = read_directory(&merged).unwrap();
+        assert_eq!(lumps[0].data, b"pwad");
+        assert_eq!(lumps[2].data, b"pwad");
+    }
+
+    #[test]
+    fn hostile_directory() {
+        let mut buf = b"PWAD".to_vec();
+        buf.extend_from_slice(&i32::MAX.to_le_bytes());
+        buf.extend_from_slice(&i32::MAX.to_le_bytes());
+
+        match read_directory(&buf) {
+            Err(Error::InvalidWad(_)) => (),
+            _ => panic!("Expected InvalidWad"),
+        }
+    }
+
+    #[test]
+    fn missing_nodes() {
+        let map = room();
+        let iwad = WadBuilder::new()
+            .marker("E1M1")
+            .lump("THINGS", vec![])
+            .lump("LINEDEFS", room_lump(&map, b"LINEDEFS"))
+            .lump("SIDEDEFS", room_lump(&map, b"SIDEDEFS"))
+            .lump("VERTEXES", room_lump(&map, b"VERTEXES"))
+            .lump("SECTORS", room_lump(&map, b"SECTORS"))
+            .build();
+
+        let merged = merge_wads(&iwad, &[]).unwrap();
+
+        assert_eq!(
+            names(&merged),
+            [
+                "E1M1", "THINGS", "LINEDEFS", "SIDEDEFS", "VERTEXES", "SEGS", "SSECTORS", "NODES",
+                "SECTORS"
+            ]
+        );
+    }
+
+    #[test]
+    fn render_without_nodes() {
+        let map = MapData {
+            segs: vec![],
+            subsectors: vec![],
+            nodes: vec![],
+            ..room()
+        };
+        let wad = map.add_to(resources(), "E1M1").build();
+        let wad = parse_wads(&wad, &[]).unwrap();
+
+        let mut state = crate::renderer::State::new(&wad, "E1M1").unwrap();
+        let (pos, dir) = state.spawn_point().unwrap();
+
+        let mut pal = [0; 768];
+        let mut buf = [0; 320 * 200];
+        state
+            .render(crate::Input {
+                pal: &mut pal,
+                buf: &mut buf,
+                width: 320,
+                height: 200,
+                pixel_aspect: crate::VANILLA_PIXEL_ASPECT,
+                pos,
+                dir,
+                view_z: None,
+                pitch: 0.,
+                fov: crate::DEFAULT_FOV,
+                palette: 0,
+                tic: 0,
+                weapon: None,
+                speed: 0.,
+                status_bar: None,
+            })
+            .unwrap();
+
+        assert_eq!(buf[100 * 320 + 160], WALL_COLOR);
+    }
+}
@@ -0,0 +1,300 @@
+use crate::blockmap::Blockmap;
+use crate::bsp_traverser::sector_at;
+use cgmath::prelude::*;
+use cgmath::{vec2, Vector2};
+
+pub const PLAYER_RADIUS: f32 = 16.;
+const PLAYER_HEIGHT: f32 = 56.;
+const MAX_STEP_HEIGHT: f32 = 24.;
+
+// Smallest radius used for collision. A box with no size cannot cross a
+// line, so thinner players would walk through walls.
+const MIN_RADIUS: f32 = 1.;
+
+// Linedef flags
+const BLOCKING: u16 = 0x0001;
+
+/// Floor and ceiling heights of the opening around a position
+struct Position {
+    floor: f32,
+    ceil: f32,
+}
+
+pub struct Collision<'m> {
+    map: &'m wad_map::Map,
+    blockmap: &'m Blockmap,
+}
+
+fn vertex(map: &wad_map::Map, index: u16) -> Vector2<f32> {
+    let v = &map.vertexes[index as usize];
+    vec2(v.x as f32, v.y as f32)
+}
+
+/// Whether a line from `a` to `b` passes through the square of the given
+/// radius around `pos`
+fn crosses_box(a: Vector2<f32>, b: Vector2<f32>, pos: Vector2<f32>, radius: f32) -> bool {
+    let outside = a.x.max(b.x) <= pos.x - radius
+        || a.x.min(b.x) >= pos.x + radius
+        || a.y.max(b.y) <= pos.y - radius
+        || a.y.min(b.y) >= pos.y + radius;
+    if outside {
+        return false;
+    }
+
+    let sides = [(-1., -1.), (1., -1.), (1., 1.), (-1., 1.)]
+        .iter()
+        .map(|&(x, y)| (pos + vec2(x, y) * radius - a).perp_dot(b - a))
+        .collect::<Vec<_>>();
+
+    sides.iter().any(|&s| s > 0.) && sides.iter().any(|&s| s < 0.)
+}
+
+fn distance_to_line(p: Vector2<f32>, a: Vector2<f32>, b: Vector2<f32>) -> f32 {
+    let d = b - a;
+    let t = ((p - a).dot(d) / d.magnitude2()).max(0.).min(1.);
+    (a + d * t - p).magnitude()
+}
+
+impl<'m> Collision<'m> {
+    pub fn new(map: &'m wad_map::Map, blockmap: &'m Blockmap) -> Collision<'m> {
+        Collision { map, blockmap }
+    }
+
+    pub fn floor_height(&self, pos: Vector2<f32>) -> Option<f32> {
+        sector_at(self.map, pos).map(|sector| sector.floor_height as f32)
+    }
+
+    /// The opening at the given position, or `None` if a line that cannot be
+    /// passed at any height is in the way
+    fn check_position(&self, pos: Vector2<f32>, radius: f32) -> Option<Position> {
+        let sector = sector_at(self.map, pos)?;
+
+        let mut position = Position {
+            floor: sector.floor_height as f32,
+            ceil: sector.ceil_height as f32,
+        };
+
+        let lines = self.blockmap.lines_in(
+            pos.x - radius,
+            pos.y - radius,
+            pos.x + radius,
+            pos.y + radius,
+        );
+
+        for line in lines {
+            let linedef = &self.map.linedefs[line as usize];
+            let (a, b) = (vertex(self.map, linedef.a), vertex(self.map, linedef.b));
+
+            if !crosses_box(a, b, pos, radius) {
+                continue;
+            }
+
+            let (front, back) = match (linedef.right_sidedef, linedef.left_sidedef) {
+                (Some(front), Some(back)) => (front, back),
+                _ => return None,
+            };
+
+            if linedef.flags & BLOCKING != 0 {
+                return None;
+            }
+
+            let front = &self.map.sectors[self.map.sidedefs[front as usize].sector_id as usize];
+            let back = &self.map.sectors[self.map.sidedefs[back as usize].sector_id as usize];
+
+            let open_floor = front.floor_height.max(back.floor_height) as f32;
+            let open_ceil = front.ceil_height.min(back.ceil_height) as f32;
+
+            position.floor = position.floor.max(open_floor);
+            position.ceil = position.ceil.min(open_ceil);
+        }
+
+        Some(position)
+    }
+
+    /// Floor and ceiling heights around the given position, including the
+    /// lines the player is touching
+    pub fn opening(&self, pos: Vector2<f32>, radius: f32) -> Option<(f32, f32)> {
+        match self.check_position(pos, radius) {
+            Some(position) => Some((position.floor, position.ceil)),
+            None => sector_at(self.map, pos)
+                .map(|sector| (sector.floor_height as f32, sector.ceil_height as f32)),
+        }
+    }
+
+    /// Whether a player standing at height `z` fits at the given position,
+    /// returning the floor height there
+    fn try_position(&self, pos: Vector2<f32>, z: f32, radius: f32) -> Option<f32> {
+        let position = self.check_position(pos, radius)?;
+
+        let fits = position.ceil - position.floor >= PLAYER_HEIGHT
+            && position.ceil - z >= PLAYER_HEIGHT
+            && position.floor - z <= MAX_STEP_HEIGHT;
+
+        if fits {
+            Some(position.floor)
+        } else {
+            None
+        }
+    }
+
+    /// The line closest to `pos` among the lines in the way at `target`
+    fn blocking_line(&self, pos: Vector2<f32>, target: Vector2<f32>, radius: f32) -> Option<u16> {
+        let lines = self
+            .blockmap
+            .lines_in(
+                target.x - radius,
+                target.y - radius,
+                target.x + radius,
+                target.y + radius,
+            )
+            .into_iter()
+            .filter(|&line| {
+                let linedef = &self.map.linedefs[line as usize];
+                crosses_box(
+                    vertex(self.map, linedef.a),
+                    vertex(self.map, linedef.b),
+                    target,
+                    radius,
+                )
+            });
+
+        lines.min_by(|&x, &y| {
+            let distance = |line: u16| {
+                let linedef = &self.map.linedefs[line as usize];
+                distance_to_line(
+                    pos,
+                    vertex(self.map, linedef.a),
+                    vertex(self.map, linedef.b),
+                )
+            };
+            distance(x).partial_cmp(&distance(y)).unwrap()
+        })
+    }
+
+    /// Move by at most `radius`, sliding along the blocking wall if the
+    /// direct move is blocked
+    fn step(
+        &self,
+        pos: Vector2<f32>,
+        z: f32,
+        delta: Vector2<f32>,
+        radius: f32,
+    ) -> Option<(Vector2<f32>, f32)> {
+        let mut candidates = vec![delta];
+
+        if let Some(line) = self.blocking_line(pos, pos + delta, radius) {
+            let linedef = &self.map.linedefs[line as usize];
+            let along = vertex(self.map, linedef.b) - vertex(self.map, linedef.a);
+            candidates.push(along * delta.dot(along) / along.magnitude2());
+        }
+
+        candidates.push(vec2(delta.x, 0.));
+        candidates.push(vec2(0., delta.y));
+
+        candidates
+            .into_iter()
+            .filter(|d| d.magnitude2() > 0.)
+            .find_map(|d| {
+                self.try_position(pos + d, z, radius)
+                    .map(|floor| (pos + d, floor))
+            })
+    }
+
+    /// Move the player from `pos` by `movement`, stopping at walls, steps
+    /// that are too high and openings that are too low. The player is
+    /// assumed to be standing on the floor. Returns the resolved position
+    /// and the floor height there, or `None` if `pos` is outside the map.
+    pub fn try_move(
+        &self,
+        pos: Vector2<f32>,
+        movement: Vector2<f32>,
+        radius: f32,
+    ) -> Option<(Vector2<f32>, f32)> {
+        let mut floor = self.floor_height(pos)?;
+        let mut pos = pos;
+        let radius = radius.max(MIN_RADIUS);
+
+        // Take steps no longer than the radius, so thin walls are not skipped
+        let steps = (movement.magnitude() / radius).ceil().max(1.) as usize;
+        let delta = movement / steps as f32;
+
+        for _ in 0..steps {
+            match self.step(pos, floor, delta, radius) {
+                Some((new_pos, new_floor)) => {
+                    pos = new_pos;
+                    floor = new_floor;
+                }
+                None => break,
+            }
+        }
+
+        Some((pos, floor))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::*;
+
+    fn try_move(map: MapData, pos: Vector2<f32>, movement: Vector2<f32>) -> (Vector2<f32>, f32) {
+        let wad = map.add_to(resources(), "E1M1").parse();
+        let map = wad_map::read_map(&wad.as_slice(), "E1M1").unwrap();
+        let blockmap = Blockmap::build(&map).unwrap();
+
+        Collision::new(&map, &blockmap)
+            .try_move(pos, movement, PLAYER_RADIUS)
+            .unwrap()
+    }
+
+    #[test]
+    fn wall() {
+        let (pos, floor) = try_move(room(), vec2(32., 128.), vec2(-100., 0.));
+        assert!(pos.x >= 16. && pos.x < 32.);
+        assert_eq!(pos.y, 128.);
+        assert_eq!(floor, 0.);
+    }
+
+    #[test]
+    fn slide() {
+        let (pos, _) = try_move(room(), vec2(32., 128.), vec2(-100., 50.));
+        assert!(pos.x >= 16. && pos.x < 32.);
+        assert!((pos.y - 178.).abs() < 0.01);
+    }
+
+    #[test]
+    fn step_up() {
+        let (pos, floor) = try_move(step_room(24, 128), vec2(100., 128.), vec2(60., 0.));
+        assert_eq!(pos, vec2(160., 128.));
+        assert_eq!(floor, 24.);
+
+        let (pos, floor) = try_move(step_room(25, 128), vec2(100., 128.), vec2(60., 0.));
+        assert!(pos.x <= 112.);
+        assert_eq!(floor, 0.);
+    }
+
+    #[test]
+    fn zero_radius() {
+        let wad = room().add_to(resources(), "E1M1").parse();
+        let map = wad_map::read_map(&wad.as_slice(), "E1M1").unwrap();
+        let blockmap = Blockmap::build(&map).unwrap();
+        let collision = Collision::new(&map, &blockmap);
+
+        let (pos, _) = collision
+            .try_move(vec2(32., 128.), vec2(10., 0.), 0.)
+            .unwrap();
+        assert_eq!(pos, vec2(42., 128.));
+
+        // The west wall still stops the player
+        let (pos, _) = collision
+            .try_move(vec2(32., 128.), vec2(-100., 0.), 0.)
+            .unwrap();
+        assert!(pos.x > 0. && pos.x < 32.);
+    }
+
+    #[test]
+    fn low_ceiling() {
+        let (pos, _) = try_move(step_room(0, 50), vec2(100., 128.), vec2(60., 0.));
+        assert!(pos.x <= 112.);
+    }
+}
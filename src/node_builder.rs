@@ -0,0 +1,512 @@
+//! Builds BSP nodes for maps that lack them, such as maps saved by an editor
+//! without running a node builder.
+//!
+//! The builder works on raw map lumps and produces VERTEXES, SEGS, SSECTORS
+//! and NODES lumps in the vanilla format, so the result is read by `wad_map`
+//! like any other map.
+
+use std::convert::{TryFrom, TryInto};
+
+const VERTEX_SIZE: usize = 4;
+const LINEDEF_SIZE: usize = 14;
+const SEG_SIZE: usize = 12;
+const SUBSECTOR_SIZE: usize = 4;
+const NODE_SIZE: usize = 28;
+
+const NO_SIDEDEF: u16 = 0xffff;
+const SUBSECTOR: u16 = 0x8000;
+
+// Candidate partitions examined per node. Larger maps sample evenly among
+// their segs to keep the build time reasonable.
+const MAX_CANDIDATES: usize = 128;
+
+// How much a split costs relative to an unbalanced tree
+const SPLIT_COST: usize = 8;
+
+type Point = (i64, i64);
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_i16(buf: &[u8], offset: usize) -> i16 {
+    i16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn put_i16(buf: &mut Vec<u8>, x: i16) {
+    buf.extend_from_slice(&x.to_le_bytes());
+}
+
+fn put_u16(buf: &mut Vec<u8>, x: u16) {
+    buf.extend_from_slice(&x.to_le_bytes());
+}
+
+#[derive(Clone)]
+struct Seg {
+    start: usize,
+    end: usize,
+    linedef: u16,
+    direction: u16,
+    offset: f64,
+}
+
+enum Side {
+    Right,
+    Left,
+    Split(Point),
+}
+
+/// Partition line through `origin` along `dir`. Points with a negative cross
+/// product are on the right, as in `BspTraverser`.
+fn cross(origin: Point, dir: Point, p: Point) -> i64 {
+    dir.0 * (p.1 - origin.1) - dir.1 * (p.0 - origin.0)
+}
+
+fn distance(a: Point, b: Point) -> f64 {
+    (((b.0 - a.0) as f64).powi(2) + ((b.1 - a.1) as f64).powi(2)).sqrt()
+}
+
+struct Builder {
+    vertexes: Vec<Point>,
+    segs: Vec<Seg>,
+    subsectors: Vec<(u16, u16)>,
+    nodes: Vec<Vec<u8>>,
+}
+
+impl Builder {
+    fn line(&self, seg: &Seg) -> (Point, Point) {
+        let a = self.vertexes[seg.start];
+        let b = self.vertexes[seg.end];
+        (a, (b.0 - a.0, b.1 - a.1))
+    }
+
+    fn side(&self, partition: &Seg, seg: &Seg) -> Side {
+        let (origin, dir) = self.line(partition);
+        let a = self.vertexes[seg.start];
+        let b = self.vertexes[seg.end];
+        let (sa, sb) = (cross(origin, dir, a), cross(origin, dir, b));
+
+        if sa == 0 && sb == 0 {
+            // Collinear segs go on the right if they face the same way
+            let (_, seg_dir) = self.line(seg);
+            if dir.0 * seg_dir.0 + dir.1 * seg_dir.1 > 0 {
+                Side::Right
+            } else {
+                Side::Left
+            }
+        } else if sa <= 0 && sb <= 0 {
+            Side::Right
+        } else if sa >= 0 && sb >= 0 {
+            Side::Left
+        } else {
+            let t = sa as f64 / (sa - sb) as f64;
+            let p = (
+                (a.0 as f64 + t * (b.0 - a.0) as f64).round() as i64,
+                (a.1 as f64 + t * (b.1 - a.1) as f64).round() as i64,
+            );
+
+            // Rounding the split point onto an endpoint means the seg barely
+            // crosses the partition
+            if p == a {
+                if sb < 0 {
+                    Side::Right
+                } else {
+                    Side::Left
+                }
+            } else if p == b {
+                if sa < 0 {
+                    Side::Right
+                } else {
+                    Side::Left
+                }
+            } else {
+                Side::Split(p)
+            }
+        }
+    }
+
+    fn is_convex(&self, segs: &[Seg]) -> bool {
+        segs.iter().all(|partition| {
+            segs.iter()
+                .all(|seg| matches!(self.side(partition, seg), Side::Right))
+        })
+    }
+
+    /// Cost of partitioning along the given seg, or `None` if it leaves
+    /// one side empty
+    fn cost(&self, partition: &Seg, segs: &[Seg]) -> Option<usize> {
+        let (mut right, mut left, mut splits) = (0usize, 0usize, 0usize);
+        for seg in segs {
+            match self.side(partition, seg) {
+                Side::Right => right += 1,
+                Side::Left => left += 1,
+                Side::Split(_) => splits += 1,
+            }
+        }
+
+        if right + splits == 0 || left + splits == 0 {
+            return None;
+        }
+
+        Some(splits * SPLIT_COST + right.abs_diff(left))
+    }
+
+    fn best_partition<'s>(
+        &self,
+        candidates: impl Iterator<Item = &'s Seg>,
+        segs: &[Seg],
+    ) -> Option<Seg> {
+        candidates
+            .filter_map(|seg| self.cost(seg, segs).map(|cost| (cost, seg)))
+            .min_by_key(|&(cost, _)| cost)
+            .map(|(_, seg)| seg.clone())
+    }
+
+    fn choose_partition(&self, segs: &[Seg]) -> Seg {
+        let step = (segs.len() / MAX_CANDIDATES).max(1);
+
+        // A non-convex set always has a seg with other segs on its left, but
+        // the sample may have missed it
+        self.best_partition(segs.iter().step_by(step), segs)
+            .or_else(|| self.best_partition(segs.iter(), segs))
+            .unwrap()
+    }
+
+    fn split(&mut self, partition: &Seg, segs: Vec<Seg>) -> (Vec<Seg>, Vec<Seg>) {
+        let (mut right, mut left) = (vec![], vec![]);
+
+        for seg in segs {
+            match self.side(partition, &seg) {
+                Side::Right => right.push(seg),
+                Side::Left => left.push(seg),
+                Side::Split(p) => {
+                    let (origin, dir) = self.line(partition);
+                    let start_is_right = cross(origin, dir, self.vertexes[seg.start]) < 0;
+
+                    self.vertexes.push(p);
+                    let split = self.vertexes.len() - 1;
+
+                    let first = Seg {
+                        end: split,
+                        ..seg.clone()
+                    };
+                    let second = Seg {
+                        start: split,
+                        offset: seg.offset + distance(self.vertexes[seg.start], p),
+                        ..seg
+                    };
+
+                    if start_is_right {
+                        right.push(first);
+                        left.push(second);
+                    } else {
+                        left.push(first);
+                        right.push(second);
+                    }
+                }
+            }
+        }
+
+        (right, left)
+    }
+
+    /// Bounding box as (top, bottom, left, right), as stored in NODES
+    fn bbox(&self, segs: &[Seg]) -> [i16; 4] {
+        let points = segs
+            .iter()
+            .flat_map(|seg| vec![self.vertexes[seg.start], self.vertexes[seg.end]])
+            .collect::<Vec<_>>();
+
+        [
+            points.iter().map(|p| p.1).max().unwrap() as i16,
+            points.iter().map(|p| p.1).min().unwrap() as i16,
+            points.iter().map(|p| p.0).min().unwrap() as i16,
+            points.iter().map(|p| p.0).max().unwrap() as i16,
+        ]
+    }
+
+    /// Build the subtree for the given segs, returning its child reference,
+    /// or `None` if the tree outgrows the 16 bit indices of the lumps
+    fn build(&mut self, segs: Vec<Seg>) -> Option<u16> {
+        if self.is_convex(&segs) {
+            let first = u16::try_from(self.segs.len()).ok()?;
+            let count = u16::try_from(segs.len()).ok()?;
+            let index = child_index(self.subsectors.len())?;
+            self.subsectors.push((count, first));
+            self.segs.extend(segs);
+            return Some(SUBSECTOR | index);
+        }
+
+        let partition = self.choose_partition(&segs);
+        let (right, left) = self.split(&partition, segs);
+        let (right_bbox, left_bbox) = (self.bbox(&right), self.bbox(&left));
+
+        let right_child = self.build(right)?;
+        let left_child = self.build(left)?;
+
+        let (origin, mut dir) = self.line(&partition);
+        while dir.0.abs() > i16::MAX as i64 || dir.1.abs() > i16::MAX as i64 {
+            dir = (dir.0 / 2, dir.1 / 2);
+        }
+
+        let mut node = vec![];
+        for &x in &[origin.0, origin.1, dir.0, dir.1] {
+            put_i16(&mut node, x as i16);
+        }
+        for &x in right_bbox.iter().chain(&left_bbox) {
+            put_i16(&mut node, x);
+        }
+        put_u16(&mut node, right_child);
+        put_u16(&mut node, left_child);
+
+        // Children come first, so the root ends up last
+        let index = child_index(self.nodes.len())?;
+        self.nodes.push(node);
+        Some(index)
+    }
+}
+
+/// Index as a child reference, which must leave the `SUBSECTOR` bit clear
+fn child_index(index: usize) -> Option<u16> {
+    u16::try_from(index)
+        .ok()
+        .filter(|&index| index & SUBSECTOR == 0)
+}
+
+pub struct NodeLumps {
+    pub vertexes: Vec<u8>,
+    pub segs: Vec<u8>,
+    pub subsectors: Vec<u8>,
+    pub nodes: Vec<u8>,
+}
+
+/// Build nodes from the VERTEXES and LINEDEFS lumps of a map. Returns `None`
+/// if the map has no linedefs to build from, or is too large for the vanilla
+/// lump format.
+pub fn build_nodes(vertexes: &[u8], linedefs: &[u8]) -> Option<NodeLumps> {
+    let vertexes = vertexes
+        .chunks_exact(VERTEX_SIZE)
+        .map(|v| (read_i16(v, 0) as i64, read_i16(v, 2) as i64))
+        .collect::<Vec<_>>();
+
+    let mut segs = vec![];
+    for (i, linedef) in linedefs.chunks_exact(LINEDEF_SIZE).enumerate() {
+        let (a, b) = (read_u16(linedef, 0) as usize, read_u16(linedef, 2) as usize);
+        let (right, left) = (read_u16(linedef, 10), read_u16(linedef, 12));
+
+        if a >= vertexes.len() || b >= vertexes.len() || vertexes[a] == vertexes[b] {
+            continue;
+        }
+
+        let linedef = u16::try_from(i).ok()?;
+        let seg = |start, end, direction| Seg {
+            start,
+            end,
+            linedef,
+            direction,
+            offset: 0.,
+        };
+
+        if right != NO_SIDEDEF {
+            segs.push(seg(a, b, 0));
+        }
+        if left != NO_SIDEDEF {
+            segs.push(seg(b, a, 1));
+        }
+    }
+
+    if segs.is_empty() {
+        return None;
+    }
+
+    let mut builder = Builder {
+        vertexes,
+        segs: vec![],
+        subsectors: vec![],
+        nodes: vec![],
+    };
+    builder.build(segs)?;
+
+    let mut lumps = NodeLumps {
+        vertexes: vec![],
+        segs: vec![],
+        subsectors: vec![],
+        nodes: builder.nodes.concat(),
+    };
+
+    for &(x, y) in &builder.vertexes {
+        put_i16(&mut lumps.vertexes, x as i16);
+        put_i16(&mut lumps.vertexes, y as i16);
+    }
+
+    for seg in &builder.segs {
+        let (_, dir) = builder.line(seg);
+        let angle = (dir.1 as f64).atan2(dir.0 as f64) / (2. * std::f64::consts::PI);
+        let angle = (angle * 65536.) as i32 as u16;
+
+        put_u16(&mut lumps.segs, u16::try_from(seg.start).ok()?);
+        put_u16(&mut lumps.segs, u16::try_from(seg.end).ok()?);
+        put_u16(&mut lumps.segs, angle);
+        put_u16(&mut lumps.segs, seg.linedef);
+        put_u16(&mut lumps.segs, seg.direction);
+        put_i16(&mut lumps.segs, seg.offset.round() as i16);
+    }
+
+    for &(count, first) in &builder.subsectors {
+        put_u16(&mut lumps.subsectors, count);
+        put_u16(&mut lumps.subsectors, first);
+    }
+
+    Some(lumps)
+}
+
+/// Check that the node lumps of a map exist and only refer to things that
+/// exist, so they can be traversed safely
+pub fn nodes_are_valid(
+    vertexes: &[u8],
+    linedefs: &[u8],
+    segs: &[u8],
+    subsectors: &[u8],
+    nodes: &[u8],
+) -> bool {
+    let num_vertexes = vertexes.len() / VERTEX_SIZE;
+    let num_linedefs = linedefs.len() / LINEDEF_SIZE;
+    let num_segs = segs.len() / SEG_SIZE;
+    let num_subsectors = subsectors.len() / SUBSECTOR_SIZE;
+    let num_nodes = nodes.len() / NODE_SIZE;
+
+    let segs_ok = segs.chunks_exact(SEG_SIZE).all(|seg| {
+        (read_u16(seg, 0) as usize) < num_vertexes
+            && (read_u16(seg, 2) as usize) < num_vertexes
+            && (read_u16(seg, 6) as usize) < num_linedefs
+    });
+
+    let subsectors_ok = subsectors.chunks_exact(SUBSECTOR_SIZE).all(|subsector| {
+        let count = read_u16(subsector, 0) as usize;
+        let first = read_u16(subsector, 2) as usize;
+        count > 0 && first + count <= num_segs
+    });
+
+    // Node builders write children before their parents, which also rules
+    // out cycles that would make traversal loop forever
+    let child_ok = |child: u16, parent: usize| {
+        if child & SUBSECTOR != 0 {
+            ((child & !SUBSECTOR) as usize) < num_subsectors
+        } else {
+            (child as usize) < parent
+        }
+    };
+
+    let nodes_ok = nodes
+        .chunks_exact(NODE_SIZE)
+        .enumerate()
+        .all(|(i, node)| child_ok(read_u16(node, 24), i) && child_ok(read_u16(node, 26), i));
+
+    // Without nodes, the whole map must be a single subsector
+    let tree_ok = num_nodes > 0 || num_subsectors == 1;
+
+    num_segs > 0 && num_subsectors > 0 && segs_ok && subsectors_ok && nodes_ok && tree_ok
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::*;
+
+    fn lumps(map: &MapData) -> (Vec<u8>, Vec<u8>) {
+        let wad = map.add_to(WadBuilder::new(), "E1M1").parse();
+        let wad = wad.as_slice();
+        (
+            wad.by_id(b"VERTEXES").unwrap().to_vec(),
+            wad.by_id(b"LINEDEFS").unwrap().to_vec(),
+        )
+    }
+
+    #[test]
+    fn convex_room() {
+        let (vertexes, linedefs) = lumps(&room());
+        let built = build_nodes(&vertexes, &linedefs).unwrap();
+
+        assert_eq!(built.nodes.len(), 0);
+        assert_eq!(built.subsectors, [4, 0, 0, 0]);
+        assert_eq!(built.segs.len(), 4 * SEG_SIZE);
+        assert!(nodes_are_valid(
+            &built.vertexes,
+            &linedefs,
+            &built.segs,
+            &built.subsectors,
+            &built.nodes
+        ));
+    }
+
+    #[test]
+    fn room_with_pillar() {
+        let mut map = room();
+
+        // A square pillar in the middle, facing out
+        let first = map.vertexes.len() as u16;
+        map.vertexes
+            .extend_from_slice(&[(96, 96), (160, 96), (160, 160), (96, 160)]);
+        for i in 0..4 {
+            map.linedefs
+                .push((first + i, first + (i + 1) % 4, 0x0001, Some(0), None));
+        }
+
+        let (vertexes, linedefs) = lumps(&map);
+        let built = build_nodes(&vertexes, &linedefs).unwrap();
+
+        assert!(!built.nodes.is_empty());
+        assert!(nodes_are_valid(
+            &built.vertexes,
+            &linedefs,
+            &built.segs,
+            &built.subsectors,
+            &built.nodes
+        ));
+    }
+
+    #[test]
+    fn too_many_linedefs() {
+        // Degenerate linedefs are skipped, but still push the real ones past
+        // the indices a seg can refer to
+        let (vertexes, linedefs) = lumps(&room());
+        let mut padded = vec![0; 0x10000 * LINEDEF_SIZE];
+        padded.extend_from_slice(&linedefs);
+
+        assert!(build_nodes(&vertexes, &padded).is_none());
+    }
+
+    #[test]
+    fn validation() {
+        let wad = room_wad();
+        let wad = wad.as_slice();
+        let lump = |name: &[u8; 8]| wad.by_id(name).unwrap();
+
+        let (vertexes, linedefs) = (lump(b"VERTEXES"), lump(b"LINEDEFS"));
+        let (segs, subsectors, nodes) = (
+            lump(b"SEGS\0\0\0\0"),
+            lump(b"SSECTORS"),
+            lump(b"NODES\0\0\0"),
+        );
+
+        assert!(nodes_are_valid(vertexes, linedefs, segs, subsectors, nodes));
+        assert!(!nodes_are_valid(vertexes, linedefs, &[], &[], &[]));
+        assert!(!nodes_are_valid(vertexes, linedefs, segs, subsectors, &[]));
+        assert!(!nodes_are_valid(
+            &vertexes[..8],
+            linedefs,
+            segs,
+            subsectors,
+            nodes
+        ));
+
+        // A node that is its own child
+        let mut looping = nodes.to_vec();
+        let last = looping.len() - NODE_SIZE;
+        let index = (looping.len() / NODE_SIZE - 1) as u16;
+        looping[last + 24..last + 26].copy_from_slice(&index.to_le_bytes());
+        assert!(!nodes_are_valid(
+            vertexes, linedefs, segs, subsectors, &looping
+        ));
+    }
+}
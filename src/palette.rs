@@ -0,0 +1,71 @@
+pub const PALETTE_SIZE: usize = 768;
+
+/// PLAYPAL holds the normal palette followed by the red damage and berserk
+/// palettes, the yellow pickup palettes and the green radiation suit palette
+pub const NUM_PALETTES: usize = 14;
+
+/// Vanilla's gamma correction levels, from off to brightest
+pub const GAMMA_LEVELS: usize = 5;
+
+/// Vanilla's `gammatable` row for the given level. Like vanilla, every
+/// level brightens colors slightly: level 0 maps each value to the next
+/// one, and the others raise (i + 1) / 256 to the power of 1 - level / 8,
+/// as the vanilla tables were generated.
+pub fn gamma_table(level: usize) -> [u8; 256] {
+    let level = level.min(GAMMA_LEVELS - 1);
+    let exponent = 1. - level as f64 / 8.;
+
+    let mut table = [0; 256];
+    for (i, x) in table.iter_mut().enumerate() {
+        *x = if level == 0 {
+            (i + 1).min(255) as u8
+        } else {
+            (255. * ((i + 1) as f64 / 256.).powf(exponent)).round() as u8
+        };
+    }
+    table
+}
+
+/// The colors of the given palette in PLAYPAL, which must hold at least one
+/// palette. Indexes past the last palette give the last one.
+pub fn palette(playpal: &[u8], index: usize) -> &[u8] {
+    let count = playpal.len() / PALETTE_SIZE;
+    let index = index.min(count - 1);
+    &playpal[index * PALETTE_SIZE..(index + 1) * PALETTE_SIZE]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::*;
+
+    #[test]
+    fn gamma() {
+        let first = gamma_table(0);
+        assert_eq!(first[..8], [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(first[254..], [255, 255]);
+
+        // The start of vanilla's second and last tables
+        assert_eq!(gamma_table(1)[..8], [2, 4, 5, 7, 8, 10, 11, 12]);
+        assert_eq!(gamma_table(4)[..8], [16, 23, 28, 32, 36, 39, 42, 45]);
+
+        for level in 1..GAMMA_LEVELS {
+            let table = gamma_table(level);
+            assert_eq!(table[255], 255);
+            assert!(table.windows(2).all(|x| x[0] <= x[1]));
+            assert!(table[128] > gamma_table(level - 1)[128]);
+        }
+
+        assert_eq!(gamma_table(9)[..], gamma_table(4)[..]);
+    }
+
+    #[test]
+    fn palettes() {
+        let playpal = playpal();
+        assert_eq!(playpal.len(), NUM_PALETTES * PALETTE_SIZE);
+
+        assert_eq!(palette(&playpal, 0), &playpal[..PALETTE_SIZE]);
+        assert_eq!(palette(&playpal, 13), &playpal[13 * PALETTE_SIZE..]);
+        assert_eq!(palette(&playpal, 20), palette(&playpal, 13));
+    }
+}
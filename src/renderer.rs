@@ -1,38 +1,180 @@
-use crate::{bsp_traverser::*, rendering_state::*, util::*, Input};
+use crate::{
+    blockmap::Blockmap, bsp_traverser::*, camera::*, error::Error, hud::*, lighting::*,
+    movement::*, palette, rendering_state::*, scrolling::*, status_bar::*, things::*, util::*,
+    Input, SCREEN_HEIGHT,
+};
 use cgmath::{vec2, Vector2};
 use ndarray::prelude::*;
 use wad::Wad;
-use wad_gfx::Sprite;
 
 const TAU: f32 = 2. * ::std::f32::consts::PI;
-const EYE_HEIGHT: f32 = 40.;
-
-struct DeferredWall {
-    floor: f32,
-    ceil: f32,
-    a: Vector2<f32>,
-    b: Vector2<f32>,
-    texture: [u8; 8],
-    clip_state: ClipState,
+
+// Linedef flags
+const UPPER_UNPEGGED: u16 = 0x0008;
+const LOWER_UNPEGGED: u16 = 0x0010;
+
+/// The sky texture is chosen by episode in Doom and by map number in Doom II
+fn sky_texture_for_map(map_name: &str) -> [u8; 8] {
+    let sky = if map_name.starts_with("MAP") {
+        match map_name[3..].parse::<u32>().unwrap_or(1) {
+            0..=11 => 1,
+            12..=20 => 2,
+            _ => 3,
+        }
+    } else {
+        match map_name.as_bytes().get(1) {
+            Some(episode @ b'1'..=b'4') => episode - b'0',
+            _ => 1,
+        }
+    };
+
+    let mut name = *b"SKY0\0\0\0\0";
+    name[3] += sky;
+    name
 }
 
 pub struct State<'a> {
+    wad: wad::WadSlice<'a>,
     playpal: &'a [u8],
-    pisga0: Sprite<'a>,
+    colormap: &'a [u8],
     texture_provider: TextureProvider<'a>,
+    flat_provider: FlatProvider<'a>,
+    sprite_provider: SpriteProvider<'a>,
+    map_name: String,
     map: wad_map::Map,
-    deferred_walls: Vec<DeferredWall>,
+    blockmap: Blockmap,
+    scrollers: Scrollers,
+    camera: Camera,
+    fov: f32,
+    sky_texture: [u8; 8],
+}
+
+fn read_map(wad: &wad::WadSlice, map_name: &str) -> Result<wad_map::Map, Error> {
+    let map = wad_map::read_map(wad, map_name)
+        .map_err(|err| Error::BadMap(map_name.to_owned(), format!("{:?}", err)))?;
+
+    check_references(&map)
+        .map_err(|reason| Error::BadMap(map_name.to_owned(), reason.to_owned()))?;
+
+    Ok(map)
+}
+
+/// Check that everything the map refers to by index exists, so that
+/// rendering and collision can index without checking
+fn check_references(map: &wad_map::Map) -> Result<(), &'static str> {
+    let vertex_ok = |i: usize| i < map.vertexes.len();
+    let sidedef_ok = |i: Option<usize>| i.map_or(true, |i| i < map.sidedefs.len());
+    let child_ok = |child: &wad_map::Child, parent: usize| match *child {
+        wad_map::Child::Subsector(s) => (s as usize) < map.subsectors.len(),
+        // Children come before their parents, which also rules out cycles
+        wad_map::Child::Subnode(n) => (n as usize) < parent,
+    };
+
+    if !map.linedefs.iter().all(|l| {
+        vertex_ok(l.a as usize)
+            && vertex_ok(l.b as usize)
+            && sidedef_ok(l.right_sidedef.map(|i| i as usize))
+            && sidedef_ok(l.left_sidedef.map(|i| i as usize))
+    }) {
+        return Err("Linedef refers to a missing vertex or sidedef");
+    }
+
+    if !map
+        .sidedefs
+        .iter()
+        .all(|s| (s.sector_id as usize) < map.sectors.len())
+    {
+        return Err("Sidedef refers to a missing sector");
+    }
+
+    if !map.line_segments.iter().all(|seg| {
+        vertex_ok(seg.start_vertex as usize)
+            && vertex_ok(seg.end_vertex as usize)
+            && (seg.linedef as usize) < map.linedefs.len()
+    }) {
+        return Err("Seg refers to a missing vertex or linedef");
+    }
+
+    if !map
+        .subsectors
+        .iter()
+        .all(|s| s.first_seg as usize + s.seg_count as usize <= map.line_segments.len())
+    {
+        return Err("Subsector refers to missing segs");
+    }
+
+    if !map
+        .nodes
+        .iter()
+        .enumerate()
+        .all(|(i, node)| child_ok(&node.left_child, i) && child_ok(&node.right_child, i))
+    {
+        return Err("Node refers to a missing or later node or subsector");
+    }
+
+    // Without nodes, the map is the single subsector 0
+    if map.subsectors.is_empty() {
+        return Err("No subsectors");
+    }
+
+    Ok(())
+}
+
+/// The BLOCKMAP of the map, or a freshly built one if it is missing or broken
+fn read_blockmap(
+    wad: &wad::WadSlice,
+    map_name: &str,
+    map: &wad_map::Map,
+) -> Result<Blockmap, Error> {
+    map_lump(wad, map_name, "BLOCKMAP")
+        .and_then(|lump| Blockmap::parse(lump, map.linedefs.len()))
+        .or_else(|| Blockmap::build(map))
+        .ok_or_else(|| {
+            Error::BadMap(
+                map_name.to_owned(),
+                "Map too large for a blockmap".to_owned(),
+            )
+        })
 }
 
 impl<'a> State<'a> {
-    pub fn new(wad: &Wad) -> State {
-        State {
-            playpal: wad.by_id(b"PLAYPAL").unwrap(),
-            pisga0: Sprite::new(wad.by_id(b"PISGA0").unwrap()),
-            texture_provider: TextureProvider::new(wad.as_slice()),
-            map: wad_map::read_map(&wad.as_slice(), "E1M1").unwrap(),
-            deferred_walls: vec![],
-        }
+    pub fn new(wad: &'a Wad, map_name: &str) -> Result<State<'a>, Error> {
+        let wad = wad.as_slice();
+        let map = read_map(&wad, map_name)?;
+
+        let mut state = State {
+            playpal: required_lump_of_size(&wad, "PLAYPAL", palette::PALETTE_SIZE)?,
+            colormap: required_lump_of_size(
+                &wad,
+                "COLORMAP",
+                NUM_COLORMAPS as usize * COLORMAP_SIZE,
+            )?,
+            texture_provider: TextureProvider::new(wad.slice(..))?,
+            flat_provider: FlatProvider::new(wad.slice(..))?,
+            sprite_provider: SpriteProvider::new(wad.slice(..))?,
+            map_name: map_name.to_owned(),
+            blockmap: read_blockmap(&wad, map_name, &map)?,
+            scrollers: Scrollers::new(&map),
+            map,
+            camera: Camera::new(0.),
+            fov: DEFAULT_FOV,
+            sky_texture: sky_texture_for_map(map_name),
+            wad,
+        };
+        state.camera = Camera::new(state.spawn_floor());
+
+        Ok(state)
+    }
+
+    pub fn set_map(&mut self, map_name: &str) -> Result<(), Error> {
+        let map = read_map(&self.wad, map_name)?;
+        self.blockmap = read_blockmap(&self.wad, map_name, &map)?;
+        self.map = map;
+        self.scrollers = Scrollers::new(&self.map);
+        self.map_name = map_name.to_owned();
+        self.sky_texture = sky_texture_for_map(map_name);
+        self.camera.reset(self.spawn_floor());
+        Ok(())
     }
 
     pub fn svg_from_map(&self) -> String {
@@ -41,72 +183,227 @@ impl<'a> State<'a> {
         buf
     }
 
-    pub fn spawn_point(&self) -> (Vector2<f32>, Vector2<f32>) {
-        let spawn_thing = &self.map.things.iter().find(|&x| x.thing_type == 1).unwrap();
+    pub fn spawn_point(&self) -> Result<(Vector2<f32>, Vector2<f32>), Error> {
+        let spawn_thing = &self
+            .map
+            .things
+            .iter()
+            .find(|&x| x.thing_type == 1)
+            .ok_or_else(|| Error::BadMap(self.map_name.clone(), "No player 1 start".to_owned()))?;
         let ang = spawn_thing.ang as f32 / 360. * TAU;
-        (
+        Ok((
             vec2(spawn_thing.x as _, spawn_thing.y as _),
             vec2(ang.cos(), ang.sin()),
-        )
+        ))
     }
 
-    fn floor_height_at(&self, pos: Vector2<f32>) -> f32 {
-        for subsector in BspTraverser::new(&self.map.nodes, pos) {
-            let subsector = &self.map.subsectors[subsector as usize];
+    /// Move the player from `pos` by `movement`, colliding with the walls of
+    /// the map. Returns the resolved position and the floor height there.
+    pub fn move_player(
+        &self,
+        pos: Vector2<f32>,
+        movement: Vector2<f32>,
+        radius: f32,
+    ) -> Result<(Vector2<f32>, f32), Error> {
+        Collision::new(&self.map, &self.blockmap)
+            .try_move(pos, movement, radius)
+            .ok_or(Error::CameraOutOfBounds)
+    }
 
-            let start = subsector.first_seg as usize;
-            let end = start + subsector.seg_count as usize;
+    /// The horizontal field of view of the last rendered frame, after
+    /// limiting it to what the renderer supports
+    pub fn fov(&self) -> f32 {
+        self.fov
+    }
 
-            for line_segment in &self.map.line_segments[start..end] {
-                let linedef = &self.map.linedefs[line_segment.linedef as usize];
+    pub fn camera(&self) -> &Camera {
+        &self.camera
+    }
 
-                let a = &self.map.vertexes[line_segment.start_vertex as usize];
-                let b = &self.map.vertexes[line_segment.end_vertex as usize];
+    pub fn camera_mut(&mut self) -> &mut Camera {
+        &mut self.camera
+    }
 
-                let a = vec2(a.x as f32, a.y as f32);
-                let b = vec2(b.x as f32, b.y as f32);
+    /// Place the camera on the floor at the given position
+    pub fn reset_camera(&mut self, pos: Vector2<f32>) -> Result<(), Error> {
+        let floor = self.sector_at(pos)?.floor_height as f32;
+        self.camera.reset(floor);
+        Ok(())
+    }
 
-                let reverse = line_segment.direction != 0;
-                let right_side = ((pos - a).perp_dot(b - a) > 0.) ^ reverse;
+    /// Let the given time pass for the camera at the given position, and
+    /// return the height of the view
+    pub fn update_camera(&mut self, pos: Vector2<f32>, seconds: f32) -> Result<f32, Error> {
+        let (floor, ceil) = Collision::new(&self.map, &self.blockmap)
+            .opening(pos, PLAYER_RADIUS)
+            .ok_or(Error::CameraOutOfBounds)?;
 
-                let front_sidedef = if right_side {
-                    linedef.right_sidedef
-                } else {
-                    linedef.left_sidedef
-                };
+        self.camera.advance(floor, ceil, seconds);
 
-                if let Some(front_sidedef) = front_sidedef {
-                    let front_sidedef = &self.map.sidedefs[front_sidedef as usize];
+        Ok(self.camera.view_z(ceil))
+    }
 
-                    let front_sector = front_sidedef.sector_id;
-                    let front_sector = &self.map.sectors[front_sector as usize];
+    fn planes(&self, sector_id: u16, camera_y: f32, tic: u32) -> (Plane, Plane) {
+        let sector = &self.map.sectors[sector_id as usize];
+        (
+            Plane {
+                height: sector.floor_height as f32 - camera_y,
+                flat: sector.floor_texture,
+                light: sector.light as u8,
+                offset: self.scrollers.floor_offset(sector_id, tic),
+            },
+            Plane {
+                height: sector.ceil_height as f32 - camera_y,
+                flat: sector.ceil_texture,
+                light: sector.light as u8,
+                offset: self.scrollers.ceil_offset(sector_id, tic),
+            },
+        )
+    }
 
-                    return front_sector.floor_height as f32 + EYE_HEIGHT;
-                }
+    // Height of the floor at the player 1 start, where the camera begins
+    fn spawn_floor(&self) -> f32 {
+        self.spawn_point()
+            .ok()
+            .and_then(|(pos, _)| self.sector_at(pos).ok())
+            .map_or(0., |sector| sector.floor_height as f32)
+    }
+
+    fn sector_at(&self, pos: Vector2<f32>) -> Result<&wad_map::Sector, Error> {
+        sector_at(&self.map, pos).ok_or(Error::CameraOutOfBounds)
+    }
+
+    fn floor_height_at(&self, pos: Vector2<f32>) -> Result<f32, Error> {
+        Ok(self.sector_at(pos)?.floor_height as f32 + VIEW_HEIGHT)
+    }
+
+    fn vis_sprites(
+        &self,
+        pos: Vector2<f32>,
+        transform: cgmath::Matrix2<f32>,
+        camera_y: f32,
+    ) -> Vec<VisSprite<'a>> {
+        let mut vis_sprites = vec![];
+
+        for thing in &self.map.things {
+            let (prefix, frame) = match thing_sprite(thing) {
+                Some(x) => x,
+                None => continue,
+            };
+
+            let thing_pos = vec2(thing.x as f32, thing.y as f32);
+            let view_pos = transform * (thing_pos - pos);
+            if view_pos.y <= 0. {
+                continue;
             }
+
+            // Pick the rotation facing the camera
+            let to_thing = thing_pos - pos;
+            let thing_ang = thing.ang as f32 / 360. * TAU;
+            let rotation = to_thing.y.atan2(to_thing.x) - thing_ang + TAU * 9. / 16.;
+            let rotation = ((rotation / (TAU / 8.)).floor() as i32).rem_euclid(8) as u8 + 1;
+
+            let (sprite, flipped) = match self.sprite_provider.get_frame(prefix, frame, rotation) {
+                Some(x) => x,
+                None => continue,
+            };
+
+            let sector = match self.sector_at(thing_pos) {
+                Ok(sector) => sector,
+                Err(_) => continue,
+            };
+
+            vis_sprites.push(VisSprite {
+                pos: view_pos,
+                bottom: sector.floor_height as f32 - camera_y,
+                light_level: sector_light_level(sector.light as u8),
+                sprite,
+                flipped,
+            });
         }
-        unreachable!()
+
+        // Back to front
+        vis_sprites.sort_by(|a, b| b.pos.y.partial_cmp(&a.pos.y).unwrap());
+
+        vis_sprites
     }
 
     pub fn render(
         &mut self,
         Input {
-            buf, pal, pos, dir, ..
+            buf,
+            width,
+            height,
+            pixel_aspect,
+            pal,
+            pos,
+            dir,
+            view_z,
+            pitch,
+            fov,
+            palette: palette_index,
+            tic,
+            weapon,
+            speed,
+            status_bar,
         }: Input,
-    ) {
-        pal.clone_from_slice(&self.playpal[0..768]);
+    ) -> Result<(), Error> {
+        if width == 0 || height == 0 || buf.len() != width * height {
+            return Err(Error::InvalidInput(format!(
+                "Frame buffer of {} bytes for a {}x{} frame",
+                buf.len(),
+                width,
+                height
+            )));
+        }
+
+        // The view transform is built from the direction and must be invertible
+        let dir_length2 = dir.x * dir.x + dir.y * dir.y;
+        if !(dir_length2.is_finite() && dir_length2 > std::f32::EPSILON) {
+            return Err(Error::InvalidInput(format!(
+                "View direction ({}, {})",
+                dir.x, dir.y
+            )));
+        }
+
+        self.texture_provider.set_tic(tic);
+        self.flat_provider.set_tic(tic);
+        self.fov = fov.max(MIN_FOV).min(MAX_FOV);
+
+        pal.clone_from_slice(palette::palette(self.playpal, palette_index));
+
+        // The status bar takes the bottom of the screen, as in vanilla
+        let bar_height = status_bar.as_ref().map_or(0, |_| status_bar_rows(height));
+        let view_height = height - bar_height;
+        let (buf, bar_buf) = buf.split_at_mut(view_height * width);
+
         {
-            let mut screen = ArrayViewMut2::from_shape((200, 320), buf).unwrap();
+            let mut screen = ArrayViewMut2::from_shape((view_height, width), &mut *buf).unwrap();
             fill(&mut screen, 0);
 
-            let mut rendering_state = RenderingState::new(&mut screen);
+            let mut rendering_state =
+                RenderingState::new(&mut screen, self.colormap, self.fov, pitch, pixel_aspect);
 
             // Mysterious rotation matrix:
             let transform = cgmath::Matrix2::new(dir.y, dir.x, -dir.x, dir.y);
 
-            let camera_y = self.floor_height_at(pos);
+            let camera_y = match view_z {
+                Some(view_z) => {
+                    self.sector_at(pos)?;
+                    view_z
+                }
+                None => self.floor_height_at(pos)?,
+            };
 
-            'outer: for subsector in BspTraverser::new(&self.map.nodes, pos) {
+            let view = View {
+                pos,
+                dir,
+                fov: self.fov,
+                width: width as i32,
+            };
+            let mut traverser = BspTraverser::with_view(&self.map.nodes, &view);
+
+            while let Some(subsector) = traverser.next_visible(rendering_state.h_open()) {
                 let subsector = &self.map.subsectors[subsector as usize];
 
                 let start = subsector.first_seg as usize;
@@ -124,6 +421,9 @@ impl<'a> State<'a> {
                     let reverse = line_segment.direction != 0;
                     let right_side = ((pos - a).perp_dot(b - a) > 0.) ^ reverse;
 
+                    let world_a = a;
+                    let world_b = b;
+
                     let (front_sidedef, back_sidedef) = if right_side {
                         (linedef.right_sidedef, linedef.left_sidedef)
                     } else {
@@ -135,6 +435,20 @@ impl<'a> State<'a> {
 
                     let portal = front_sidedef.is_some() && back_sidedef.is_some();
 
+                    let (u_offset, y_offset) = match front_sidedef {
+                        Some(front_sidedef_id) => {
+                            let front_sidedef = &self.map.sidedefs[front_sidedef_id as usize];
+                            let scroll = self.scrollers.side_offset(front_sidedef_id, tic);
+                            (
+                                line_segment.offset as f32
+                                    + front_sidedef.x_offset as f32
+                                    + scroll.x,
+                                front_sidedef.y_offset as f32 + scroll.y,
+                            )
+                        }
+                        None => (0., 0.),
+                    };
+
                     if portal {
                         let front_sidedef = &self.map.sidedefs[front_sidedef.unwrap() as usize];
                         let back_sidedef = &self.map.sidedefs[back_sidedef.unwrap() as usize];
@@ -148,20 +462,37 @@ impl<'a> State<'a> {
                         let _ = self
                             .texture_provider
                             .load_texture(&front_sidedef.lower_texture);
+                        let _ = self
+                            .texture_provider
+                            .load_texture(&front_sidedef.middle_texture);
+
+                        // Upper walls between two sky sectors would hide the sky
+                        let sky_ceilings = front_sector.ceil_texture == SKY_FLAT
+                            && back_sector.ceil_texture == SKY_FLAT;
 
-                        let upper = if front_sector.ceil_height > back_sector.ceil_height {
+                        let upper = if sky_ceilings {
+                            None
+                        } else if front_sector.ceil_height > back_sector.ceil_height {
                             self.texture_provider
                                 .get_texture(&front_sidedef.upper_texture)
                                 .map(|texture| {
-                                    (
-                                        front_sector.ceil_height as f32 - camera_y,
-                                        std::cmp::max(
+                                    // Pegged upper textures hang down from the back ceiling
+                                    let texture_top = if linedef.flags & UPPER_UNPEGGED != 0 {
+                                        front_sector.ceil_height as f32
+                                    } else {
+                                        back_sector.ceil_height as f32 + texture.height() as f32
+                                    };
+
+                                    WallSection {
+                                        top: front_sector.ceil_height as f32 - camera_y,
+                                        bottom: std::cmp::max(
                                             back_sector.ceil_height,
                                             front_sector.floor_height,
                                         ) as f32
                                             - camera_y,
+                                        texture_top: texture_top - camera_y + y_offset,
                                         texture,
-                                    )
+                                    }
                                 })
                         } else {
                             None
@@ -171,15 +502,24 @@ impl<'a> State<'a> {
                             self.texture_provider
                                 .get_texture(&front_sidedef.lower_texture)
                                 .map(|texture| {
-                                    (
-                                        std::cmp::min(
+                                    // Unpegged lower textures are aligned as if they
+                                    // started at the front ceiling
+                                    let texture_top = if linedef.flags & LOWER_UNPEGGED != 0 {
+                                        front_sector.ceil_height
+                                    } else {
+                                        back_sector.floor_height
+                                    };
+
+                                    WallSection {
+                                        top: std::cmp::min(
                                             back_sector.floor_height,
                                             front_sector.ceil_height,
                                         ) as f32
                                             - camera_y,
-                                        front_sector.floor_height as f32 - camera_y,
+                                        bottom: front_sector.floor_height as f32 - camera_y,
+                                        texture_top: texture_top as f32 - camera_y + y_offset,
                                         texture,
-                                    )
+                                    }
                                 })
                         } else {
                             None
@@ -193,56 +533,581 @@ impl<'a> State<'a> {
                             as f32
                             - camera_y;
 
-                        rendering_state.portal(floor, ceil, a, b, &upper, &lower);
+                        let middle = self
+                            .texture_provider
+                            .get_texture(&front_sidedef.middle_texture)
+                            .map(|texture| {
+                                // Masked textures are not tiled, so lower unpegged
+                                // ones rest on the floor of the opening
+                                let texture_top = if linedef.flags & LOWER_UNPEGGED != 0 {
+                                    floor + texture.height() as f32
+                                } else {
+                                    ceil
+                                };
+
+                                MaskedSection {
+                                    texture: front_sidedef.middle_texture,
+                                    texture_top: texture_top + y_offset,
+                                }
+                            });
+
+                        let (front_floor, mut front_ceil) =
+                            self.planes(front_sidedef.sector_id, camera_y, tic);
+                        if sky_ceilings {
+                            // Let the sky extend down where the upper wall would have been,
+                            // to the top of the opening
+                            front_ceil.height = ceil;
+                        }
+                        let light_level =
+                            wall_light_level(front_sector.light as u8, world_a, world_b);
 
-                        self.deferred_walls.push(DeferredWall {
+                        rendering_state.portal(
+                            &front_floor,
+                            &front_ceil,
                             floor,
                             ceil,
                             a,
                             b,
-                            texture: front_sidedef.middle_texture,
-                            clip_state: rendering_state.get_clip_state(),
-                        });
+                            u_offset,
+                            light_level,
+                            &upper,
+                            &lower,
+                            &middle,
+                        );
                     } else {
                         if let Some(front_sidedef) = front_sidedef {
                             let front_sidedef = &self.map.sidedefs[front_sidedef as usize];
 
-                            let front_sector = front_sidedef.sector_id;
-                            let front_sector = &self.map.sectors[front_sector as usize];
+                            let front_sector_id = front_sidedef.sector_id;
+                            let front_sector = &self.map.sectors[front_sector_id as usize];
 
+                            // Walls with a missing texture are left out, like missing
+                            // upper and lower textures
                             let texture = &front_sidedef.middle_texture;
-                            self.texture_provider.load_texture(texture).unwrap();
-                            let texture = &self.texture_provider.get_texture(texture).unwrap();
+                            let _ = self.texture_provider.load_texture(texture);
+                            let texture = match self.texture_provider.get_texture(texture) {
+                                Some(texture) => texture,
+                                None => continue,
+                            };
+                            let texture = &texture;
+
+                            let (floor, ceil) = self.planes(front_sector_id, camera_y, tic);
+                            let light_level =
+                                wall_light_level(front_sector.light as u8, world_a, world_b);
 
-                            let floor = front_sector.floor_height as f32 - camera_y;
-                            let ceil = front_sector.ceil_height as f32 - camera_y;
+                            // Lower unpegged walls have the bottom of the texture at the floor
+                            let texture_top = if linedef.flags & LOWER_UNPEGGED != 0 {
+                                floor.height + texture.height() as f32
+                            } else {
+                                ceil.height
+                            };
 
-                            rendering_state.wall(floor, ceil, a, b, texture);
+                            rendering_state.wall(
+                                &floor,
+                                &ceil,
+                                a,
+                                b,
+                                u_offset,
+                                light_level,
+                                texture_top + y_offset,
+                                texture,
+                            );
                         }
                     }
                 }
 
                 if rendering_state.is_complete() {
-                    break 'outer;
+                    break;
                 }
             }
 
-            for deferred_wall in self.deferred_walls.drain(..).rev() {
-                let _ = self.texture_provider.load_texture(&deferred_wall.texture);
-                if let Some(texture) = &self.texture_provider.get_texture(&deferred_wall.texture) {
-                    rendering_state.set_clip_state(deferred_wall.clip_state);
-                    rendering_state.wall(
-                        deferred_wall.floor,
-                        deferred_wall.ceil,
-                        deferred_wall.a,
-                        deferred_wall.b,
-                        texture,
-                    );
-                }
+            let _ = self.texture_provider.load_texture(&self.sky_texture);
+            let sky = self.texture_provider.get_texture(&self.sky_texture);
+
+            rendering_state.draw_planes(&self.flat_provider, sky.as_ref(), pos, transform);
+
+            let vis_sprites = self.vis_sprites(pos, transform, camera_y);
+            let textures = &self.texture_provider;
+            rendering_state.draw_masked(&vis_sprites, |name| textures.get_texture(name));
+        }
+
+        if let Some(weapon) = weapon {
+            let sprite = required_patch(&self.wad, weapon)?;
+            let mut screen = ArrayViewMut2::from_shape((view_height, width), buf).unwrap();
+            let scale = height as f32 / SCREEN_HEIGHT as f32;
+            draw_weapon(
+                &mut screen,
+                &sprite,
+                weapon_position(speed, tic),
+                scale,
+                pixel_aspect,
+            );
+        }
+
+        if let Some(status_bar) = status_bar {
+            let mut bar = ArrayViewMut2::from_shape((bar_height, width), bar_buf).unwrap();
+            status_bar.draw(&self.wad, &mut bar, pixel_aspect)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::*;
+
+    struct Options {
+        size: (usize, usize),
+        pitch: f32,
+        fov: f32,
+        weapon: Option<&'static str>,
+        status_bar: Option<StatusBar>,
+    }
+
+    impl Default for Options {
+        fn default() -> Options {
+            Options {
+                size: (320, 200),
+                pitch: 0.,
+                fov: DEFAULT_FOV,
+                weapon: None,
+                status_bar: None,
+            }
+        }
+    }
+
+    fn render(state: &mut State, pos: Vector2<f32>, dir: Vector2<f32>) -> Result<Vec<u8>, Error> {
+        render_with(state, pos, dir, Options::default())
+    }
+
+    fn render_with(
+        state: &mut State,
+        pos: Vector2<f32>,
+        dir: Vector2<f32>,
+        Options {
+            size,
+            pitch,
+            fov,
+            weapon,
+            status_bar,
+        }: Options,
+    ) -> Result<Vec<u8>, Error> {
+        let (width, height) = size;
+        let mut pal = [0; 768];
+        let mut buf = vec![0; width * height];
+        state.render(Input {
+            pal: &mut pal,
+            buf: &mut buf,
+            width,
+            height,
+            pixel_aspect: VANILLA_PIXEL_ASPECT,
+            pos,
+            dir,
+            view_z: None,
+            pitch,
+            fov,
+            palette: 0,
+            tic: 0,
+            weapon,
+            speed: 0.,
+            status_bar,
+        })?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn spawn_point() {
+        let wad = room_wad();
+        let state = State::new(&wad, "E1M1").unwrap();
+
+        assert_eq!(
+            state.spawn_point().unwrap(),
+            (vec2(32., 128.), vec2(1., 0.))
+        );
+    }
+
+    #[test]
+    fn render_room() {
+        let wad = room_wad();
+        let mut state = State::new(&wad, "E1M1").unwrap();
+        let (pos, dir) = state.spawn_point().unwrap();
+
+        let buf = render(&mut state, pos, dir).unwrap();
+        let pixel = |x: usize, y: usize| buf[y * 320 + x];
+
+        // The far wall is 224 units away, spanning rows 38 to 129 and the
+        // columns 69 to 251
+        for &x in &[100, 160, 220] {
+            assert_eq!(pixel(x, 10), CEIL_COLOR);
+            assert_eq!(pixel(x, 100), WALL_COLOR);
+            assert_eq!(pixel(x, 190), FLOOR_COLOR);
+        }
+    }
+
+    #[test]
+    fn high_resolution() {
+        let wad = room_wad();
+        let mut state = State::new(&wad, "E1M1").unwrap();
+        let (pos, dir) = state.spawn_point().unwrap();
+
+        // The same view as in render_room, at twice the size
+        let options = Options {
+            size: (640, 400),
+            ..Options::default()
+        };
+        let buf = render_with(&mut state, pos, dir, options).unwrap();
+        let pixel = |x: usize, y: usize| buf[y * 640 + x];
+
+        for &x in &[200, 320, 440] {
+            assert_eq!(pixel(x, 20), CEIL_COLOR);
+            assert_eq!(pixel(x, 200), WALL_COLOR);
+            assert_eq!(pixel(x, 380), FLOOR_COLOR);
+        }
+    }
+
+    #[test]
+    fn look_up() {
+        let wad = room_wad();
+        let mut state = State::new(&wad, "E1M1").unwrap();
+        let (pos, dir) = state.spawn_point().unwrap();
+
+        // Move the horizon 50 rows down, so the far wall spans rows 88 to 179
+        let options = Options {
+            pitch: (50f32 / 160.).atan(),
+            ..Options::default()
+        };
+        let buf = render_with(&mut state, pos, dir, options).unwrap();
+        let pixel = |x: usize, y: usize| buf[y * 320 + x];
+
+        for &x in &[100, 160, 220] {
+            assert_eq!(pixel(x, 60), CEIL_COLOR);
+            assert_eq!(pixel(x, 150), WALL_COLOR);
+            assert_eq!(pixel(x, 190), FLOOR_COLOR);
+        }
+    }
+
+    #[test]
+    fn wide_fov() {
+        let wad = room_wad();
+        let mut state = State::new(&wad, "E1M1").unwrap();
+        let (pos, dir) = state.spawn_point().unwrap();
+
+        let buf = render(&mut state, pos, dir).unwrap();
+        assert_eq!(buf[90 * 320 + 160], WALL_COLOR);
+        assert_eq!(state.fov(), DEFAULT_FOV);
+
+        // At 170 degrees the far wall shrinks to rows 95 to 102
+        let options = Options {
+            fov: 170. / 360. * TAU,
+            ..Options::default()
+        };
+        let buf = render_with(&mut state, pos, dir, options).unwrap();
+        assert_eq!(buf[90 * 320 + 160], CEIL_COLOR);
+        assert_eq!(buf[100 * 320 + 160], WALL_COLOR);
+        assert_eq!(buf[110 * 320 + 160], FLOOR_COLOR);
+
+        let options = Options {
+            fov: TAU,
+            ..Options::default()
+        };
+        render_with(&mut state, pos, dir, options).unwrap();
+        assert_eq!(state.fov(), MAX_FOV);
+    }
+
+    #[test]
+    fn weapon() {
+        let wad = room()
+            .add_to(resources(), "E1M1")
+            .lump("PISGA0", patch(10, 20, 7))
+            .parse();
+        let mut state = State::new(&wad, "E1M1").unwrap();
+        let (pos, dir) = state.spawn_point().unwrap();
+
+        let buf = render(&mut state, pos, dir).unwrap();
+        assert_ne!(buf[32 * 320 + 1], 7);
+
+        let options = Options {
+            weapon: Some("PISGA0"),
+            ..Options::default()
+        };
+        let buf = render_with(&mut state, pos, dir, options).unwrap();
+        assert_eq!(buf[32 * 320 + 1], 7);
+
+        let options = Options {
+            weapon: Some("SHTGA0"),
+            ..Options::default()
+        };
+        match render_with(&mut state, pos, dir, options) {
+            Err(Error::MissingLump(_)) => (),
+            _ => panic!("Expected MissingLump"),
+        }
+
+        // Lumps that are not pictures are rejected rather than drawn
+        let options = Options {
+            weapon: Some("PLAYPAL"),
+            ..Options::default()
+        };
+        match render_with(&mut state, pos, dir, options) {
+            Err(Error::InvalidWad(_)) => (),
+            _ => panic!("Expected InvalidWad"),
+        }
+    }
+
+    #[test]
+    fn status_bar() {
+        let mut wad = room().add_to(resources(), "E1M1");
+        wad = ["STBAR", "STARMS", "STTPRCNT", "STFST00"]
+            .iter()
+            .fold(wad, |wad, name| wad.lump(name, patch(320, 32, 7)));
+        for digit in 0..10 {
+            wad = wad
+                .lump(&format!("STTNUM{}", digit), patch(14, 16, 7))
+                .lump(&format!("STYSNUM{}", digit), patch(4, 6, 7))
+                .lump(&format!("STGNUM{}", digit), patch(4, 6, 7));
+        }
+        let wad = wad.parse();
+        let mut state = State::new(&wad, "E1M1").unwrap();
+        let (pos, dir) = state.spawn_point().unwrap();
+
+        let options = Options {
+            status_bar: Some(StatusBar {
+                health: 100,
+                armor: 0,
+                ammo: Some(50),
+                arms: [true, false, false, false, false, false],
+                keys: [false; 6],
+                ammo_counts: [(50, 200), (0, 50), (0, 50), (0, 300)],
+                face: Face::Straight(0),
+            }),
+            ..Options::default()
+        };
+        let buf = render_with(&mut state, pos, dir, options).unwrap();
+        let pixel = |x: usize, y: usize| buf[y * 320 + x];
+
+        // The view is 168 rows high, moving the far wall up to rows 22 to 113
+        for &x in &[100, 160, 220] {
+            assert_eq!(pixel(x, 20), CEIL_COLOR);
+            assert_eq!(pixel(x, 100), WALL_COLOR);
+            assert_eq!(pixel(x, 167), FLOOR_COLOR);
+        }
+        for &x in &[0, 160, 319] {
+            assert_eq!(pixel(x, 168), 7);
+            assert_eq!(pixel(x, 199), 7);
+        }
+    }
+
+    #[test]
+    fn palette_flash() {
+        let wad = room_wad();
+        let mut state = State::new(&wad, "E1M1").unwrap();
+        let (pos, dir) = state.spawn_point().unwrap();
+
+        let mut pal = [0; 768];
+        let mut buf = [0; 320 * 200];
+        let mut render_palette = |palette| {
+            state
+                .render(Input {
+                    pal: &mut pal,
+                    buf: &mut buf,
+                    width: 320,
+                    height: 200,
+                    pixel_aspect: VANILLA_PIXEL_ASPECT,
+                    pos,
+                    dir,
+                    view_z: None,
+                    pitch: 0.,
+                    fov: DEFAULT_FOV,
+                    palette,
+                    tic: 0,
+                    weapon: None,
+                    speed: 0.,
+                    status_bar: None,
+                })
+                .unwrap();
+            pal
+        };
+
+        assert_eq!(render_palette(0)[..], playpal()[..768]);
+        assert_eq!(render_palette(13)[..], playpal()[13 * 768..]);
+    }
+
+    #[test]
+    fn masked_middle() {
+        // A grate resting on the floor of the opening between the halves,
+        // under a lower ceiling in the east half
+        let mut map = step_room(0, 64);
+        map.linedefs[6].2 |= LOWER_UNPEGGED;
+        map.sidedefs[6].2 = "GRATE";
+        map.sidedefs[7].2 = "GRATE";
+
+        let wad = map
+            .add_to(resources(), "E1M1")
+            .lump("TEXTURE2", texture_directory(&[("GRATE", 64, 32, 0)]))
+            .parse();
+        let mut state = State::new(&wad, "E1M1").unwrap();
+        let (pos, dir) = state.spawn_point().unwrap();
+
+        let buf = render(&mut state, pos, dir).unwrap();
+        let pixel = |x: usize, y: usize| buf[y * 320 + x];
+
+        // The grate is 96 units away and spans rows 116 to 167. It is not
+        // repeated up to the east ceiling, which shows from row 62 to 83.
+        assert_eq!(pixel(160, 140), WALL_COLOR);
+        assert_eq!(pixel(160, 70), CEIL_COLOR);
+    }
+
+    #[test]
+    fn sky_next_to_taller_sky() {
+        // Both halves have a sky ceiling, the east one higher than the west
+        let mut map = step_room(0, 96);
+        map.sectors[0].1 = 64;
+        map.sectors[0].3 = "F_SKY1";
+        map.sectors[1].3 = "F_SKY1";
+
+        let wad = map
+            .add_to(resources(), "E1M1")
+            .lump("TEXTURE2", texture_directory(&[("SKY1", 64, 128, 0)]))
+            .parse();
+        let mut state = State::new(&wad, "E1M1").unwrap();
+        let (pos, dir) = state.spawn_point().unwrap();
+
+        let buf = render(&mut state, pos, dir).unwrap();
+
+        // The opening is 96 units away, with its top at row 62. Above it, the
+        // sky continues down to where the east ceiling would have been.
+        for y in 0..60 {
+            assert_eq!(buf[y * 320 + 160], WALL_COLOR, "row {}", y);
+        }
+    }
+
+    #[test]
+    fn camera_steps_up() {
+        let wad = step_room(24, 128).add_to(resources(), "E1M1").parse();
+        let mut state = State::new(&wad, "E1M1").unwrap();
+
+        state.reset_camera(vec2(100., 128.)).unwrap();
+        assert_eq!(
+            state.update_camera(vec2(100., 128.), 0.).unwrap(),
+            VIEW_HEIGHT
+        );
+
+        // The view rises smoothly onto the step
+        let view_z = state.update_camera(vec2(160., 128.), 0.1).unwrap();
+        assert!(view_z > VIEW_HEIGHT && view_z < 24. + VIEW_HEIGHT);
+
+        let view_z = state.update_camera(vec2(160., 128.), 1.).unwrap();
+        assert_eq!(view_z, 24. + VIEW_HEIGHT);
+    }
+
+    #[test]
+    fn camera_starts_at_spawn() {
+        let mut map = step_room(24, 128);
+        map.things = vec![(200, 128, 0, 1)];
+        let wad = map.add_to(resources(), "E1M1").parse();
+        let mut state = State::new(&wad, "E1M1").unwrap();
+
+        assert_eq!(
+            state.update_camera(vec2(200., 128.), 0.).unwrap(),
+            24. + VIEW_HEIGHT
+        );
+    }
+
+    #[test]
+    fn zero_direction() {
+        let wad = room_wad();
+        let mut state = State::new(&wad, "E1M1").unwrap();
+        let (pos, _) = state.spawn_point().unwrap();
+
+        for &dir in &[vec2(0., 0.), vec2(std::f32::NAN, 1.)] {
+            match render(&mut state, pos, dir) {
+                Err(Error::InvalidInput(_)) => (),
+                _ => panic!("Expected InvalidInput"),
             }
         }
+    }
+
+    #[test]
+    fn buffer_size() {
+        let wad = room_wad();
+        let mut state = State::new(&wad, "E1M1").unwrap();
+        let (pos, dir) = state.spawn_point().unwrap();
+
+        let mut pal = [0; 768];
+        let mut buf = vec![0; 100];
+        let result = state.render(Input {
+            pal: &mut pal,
+            buf: &mut buf,
+            width: 320,
+            height: 200,
+            pixel_aspect: VANILLA_PIXEL_ASPECT,
+            pos,
+            dir,
+            view_z: None,
+            pitch: 0.,
+            fov: DEFAULT_FOV,
+            palette: 0,
+            tic: 0,
+            weapon: None,
+            speed: 0.,
+            status_bar: None,
+        });
+        match result {
+            Err(Error::InvalidInput(_)) => (),
+            _ => panic!("Expected InvalidInput"),
+        }
+    }
+
+    #[test]
+    fn missing_middle_texture() {
+        let mut map = room();
+        map.sidedefs[1].2 = "NOWALL";
+        let wad = map.add_to(resources(), "E1M1").parse();
+        let mut state = State::new(&wad, "E1M1").unwrap();
+        let (pos, dir) = state.spawn_point().unwrap();
+
+        let buf = render(&mut state, pos, dir).unwrap();
+        assert_eq!(buf[100 * 320 + 160], WALL_COLOR);
+    }
 
-        let mut screen = ArrayViewMut2::from_shape((200, 320), buf).unwrap();
-        put_sprite(&mut screen, 0, 32, &self.pisga0);
+    #[test]
+    fn bad_references() {
+        let mut map = room();
+        map.sidedefs[0].3 = 9;
+        let wad = map.add_to(resources(), "E1M1").parse();
+        match State::new(&wad, "E1M1") {
+            Err(Error::BadMap(_, _)) => (),
+            _ => panic!("Expected BadMap"),
+        }
+
+        let mut map = room();
+        map.subsectors[0].0 = 100;
+        let wad = map.add_to(resources(), "E1M1").parse();
+        match State::new(&wad, "E1M1") {
+            Err(Error::BadMap(_, _)) => (),
+            _ => panic!("Expected BadMap"),
+        }
+    }
+
+    #[test]
+    fn camera_out_of_bounds() {
+        let wad = room_wad();
+        let mut state = State::new(&wad, "E1M1").unwrap();
+
+        match render(&mut state, vec2(-100., -100.), vec2(1., 0.)) {
+            Err(Error::CameraOutOfBounds) => (),
+            _ => panic!("Expected CameraOutOfBounds"),
+        }
+    }
+
+    #[test]
+    fn missing_map() {
+        let wad = room_wad();
+
+        match State::new(&wad, "E1M2") {
+            Err(Error::BadMap(..)) => (),
+            _ => panic!("Expected BadMap"),
+        }
     }
 }
@@ -1,69 +1,245 @@
+use crate::lighting::*;
 use crate::util::*;
-use array_macro::array;
 use cgmath::prelude::*;
-use cgmath::{vec2, vec3, Vector2, Vector3};
+use cgmath::{vec2, vec3, Matrix2, Vector2, Vector3};
 use ndarray::prelude::*;
 use std::ops::Range;
-use std::rc::Rc;
 use wad_gfx::Sprite;
 
 const TAU: f32 = 2. * ::std::f32::consts::PI;
-const PROJECTION_PLANE_WIDTH: f32 = 320.;
-const FOV: f32 = 90. * TAU / 360.;
-const PROJECTION_PLANE_HALF_WIDTH: f32 = PROJECTION_PLANE_WIDTH / 2.;
+pub const DEFAULT_FOV: f32 = 90. * TAU / 360.;
 
-pub struct ClipState {
-    h_open: Rc<Vec<Range<i32>>>,
-    v_open: Rc<[Range<i32>; 320]>,
+/// Limits of the horizontal field of view. The projection is flat, so it
+/// cannot reach 180 degrees.
+pub const MIN_FOV: f32 = 10. * TAU / 360.;
+pub const MAX_FOV: f32 = 170. * TAU / 360.;
+const CLIP_NEAR: f32 = 10.;
+
+/// Doom draws 320x200 and is displayed at 4:3, so its pixels are this many
+/// times taller than they are wide. The art is made for this stretch.
+pub const VANILLA_PIXEL_ASPECT: f32 = 1.2;
+
+// Distance to the projection plane in vanilla, in pixels of a 320 pixel
+// wide screen
+const VANILLA_PROJECTION: f32 = 160.;
+
+/// Looking up and down shears the view vertically rather than rotating it,
+/// which distorts too much beyond this angle
+pub const MAX_PITCH: f32 = TAU / 8.;
+
+pub const SKY_FLAT: [u8; 8] = *b"F_SKY1\0\0";
+
+// Row of the sky texture that is on the horizon, at vanilla scale
+const SKY_TEXTURE_MID: f32 = 100.;
+
+// The sky texture wraps around four times per revolution
+const SKY_COLUMNS_PER_REVOLUTION: f32 = 1024.;
+
+pub struct Plane {
+    pub height: f32,
+    pub flat: [u8; 8],
+    pub light: u8,
+    /// How far the flat has scrolled, in map units
+    pub offset: Vector2<f32>,
+}
+
+struct Visplane {
+    height: f32,
+    flat: [u8; 8],
+    light: u8,
+    offset: Vector2<f32>,
+    columns: Vec<Range<i32>>,
+}
+
+impl Plane {
+    fn is_sky(&self) -> bool {
+        self.flat == SKY_FLAT
+    }
+}
+
+impl Visplane {
+    fn new(plane: &Plane, width: i32) -> Visplane {
+        // All sky is drawn the same way, regardless of height and light
+        let (height, light, offset) = if plane.is_sky() {
+            (0., 0, vec2(0., 0.))
+        } else {
+            (plane.height, plane.light, plane.offset)
+        };
+
+        Visplane {
+            height,
+            flat: plane.flat,
+            light,
+            offset,
+            columns: vec![0..0; width as usize],
+        }
+    }
+
+    fn is_compatible(&self, plane: &Plane, x: i32) -> bool {
+        let same_surface = if plane.is_sky() {
+            self.flat == plane.flat
+        } else {
+            self.height == plane.height
+                && self.flat == plane.flat
+                && self.light == plane.light
+                && self.offset == plane.offset
+        };
+
+        same_surface && is_empty(&self.columns[x as usize])
+    }
+}
+
+fn texel(texture: &Sprite, column: u32, row: i32) -> Option<u8> {
+    texture.col(column).find_map(|span| {
+        let i = row - span.top as i32;
+        if i >= 0 && (i as usize) < span.pixels.len() {
+            Some(span.pixels[i as usize])
+        } else {
+            None
+        }
+    })
+}
+
+pub struct VisSprite<'s> {
+    /// Position in view space
+    pub pos: Vector2<f32>,
+    /// Height of the bottom of the thing relative to the camera
+    pub bottom: f32,
+    pub light_level: i32,
+    pub sprite: Sprite<'s>,
+    pub flipped: bool,
+}
+
+impl<'s> VisSprite<'s> {
+    fn scale(&self, distance_to_projection_plane: f32) -> f32 {
+        distance_to_projection_plane / self.pos.y
+    }
+}
+
+pub struct WallSection<'t> {
+    pub top: f32,
+    pub bottom: f32,
+    /// Height of the top row of the texture, which need not be within the wall
+    pub texture_top: f32,
+    pub texture: Sprite<'t>,
+}
+
+/// A masked texture in the middle of a portal, such as a grate or fence. It
+/// is drawn once rather than tiled, along with the sprites.
+pub struct MaskedSection {
+    pub texture: [u8; 8],
+    /// Height of the top row of the texture
+    pub texture_top: f32,
+}
+
+/// A column of a masked texture, as seen when its portal was drawn
+struct MaskedColumn<'a> {
+    x: i32,
+    scale: f32,
+    u: f32,
+    // Screen row of the top of the texture
+    top: f32,
+    // The rows left open in front of the portal and through it
+    clip: Range<i32>,
+    colormap: &'a [u8],
+}
+
+struct MaskedWall<'a> {
+    texture: [u8; 8],
+    columns: Vec<MaskedColumn<'a>>,
 }
 
 pub struct RenderingState<'a> {
+    width: i32,
+    height: i32,
+    // Horizontal and vertical distance to the projection plane, in pixels.
+    // They differ when pixels are not as tall as vanilla's.
     distance_to_projection_plane: f32,
+    vertical_distance: f32,
+    center_x: f32,
+    // Screen row of the horizon, moved by the pitch
+    center_y: f32,
     framebuffer: &'a mut ArrayViewMut2<'a, u8>,
-    h_open: Rc<Vec<Range<i32>>>,
-    v_open: Rc<[Range<i32>; 320]>,
+    colormaps: &'a [u8],
+    h_open: Vec<Range<i32>>,
+    v_open: Vec<Range<i32>>,
+    visplanes: Vec<Visplane>,
+
+    // Masked middle textures, front to back
+    masked_walls: Vec<MaskedWall<'a>>,
+
+    // For each screen column, the scale of every wall drawn in that column
+    // along with the vertical range left open behind it, front to back
+    silhouettes: Vec<Vec<(f32, Range<i32>)>>,
 }
 
 impl<'a> RenderingState<'a> {
-    pub fn new(framebuffer: &'a mut ArrayViewMut2<'a, u8>) -> RenderingState<'a> {
+    /// Set up rendering to the given framebuffer, which is displayed with
+    /// pixels `pixel_aspect` times taller than they are wide. `fov` is the
+    /// horizontal field of view in radians.
+    pub fn new(
+        framebuffer: &'a mut ArrayViewMut2<'a, u8>,
+        colormaps: &'a [u8],
+        fov: f32,
+        pitch: f32,
+        pixel_aspect: f32,
+    ) -> RenderingState<'a> {
+        let (height, width) = framebuffer.dim();
+        let (width, height) = (width as i32, height as i32);
+
+        let distance_to_projection_plane = width as f32 / 2. / (fov / 2.).tan();
+        let vertical_distance = distance_to_projection_plane * VANILLA_PIXEL_ASPECT / pixel_aspect;
+        let pitch = pitch.max(-MAX_PITCH).min(MAX_PITCH);
+
         RenderingState {
-            distance_to_projection_plane: PROJECTION_PLANE_HALF_WIDTH / (FOV / 2.).tan(),
+            width,
+            height,
+            distance_to_projection_plane,
+            vertical_distance,
+            center_x: width as f32 / 2.,
+            center_y: height as f32 / 2. + vertical_distance * pitch.tan(),
             framebuffer,
-            h_open: Rc::new(vec![0..320]),
-            v_open: Rc::new(array![0..200; 320]),
+            colormaps,
+            h_open: vec![0..width],
+            v_open: vec![0..height; width as usize],
+            visplanes: vec![],
+            masked_walls: vec![],
+            silhouettes: vec![vec![]; width as usize],
         }
     }
 
-    pub fn get_clip_state(&self) -> ClipState {
-        ClipState {
-            h_open: self.h_open.clone(),
-            v_open: self.v_open.clone(),
-        }
+    fn colormap(&self, index: usize) -> &'a [u8] {
+        let colormaps = self.colormaps;
+        &colormaps[index * COLORMAP_SIZE..(index + 1) * COLORMAP_SIZE]
     }
 
-    pub fn set_clip_state(&mut self, clip_state: ClipState) {
-        self.h_open = clip_state.h_open;
-        self.v_open = clip_state.v_open;
+    /// The colormap for a wall or sprite drawn at the given vertical scale
+    fn scaled_colormap(&self, light_level: i32, scale: f32) -> &'a [u8] {
+        self.colormap(wall_colormap(light_level, self.vertical_distance / scale))
     }
 
     fn project(&self, p: Vector3<f32>) -> Vector2<f32> {
         let w = 1. / p.z;
 
         vec2(
-            160. + self.distance_to_projection_plane * p.x * w,
-            100. - self.distance_to_projection_plane * p.y * w,
+            self.center_x + self.distance_to_projection_plane * p.x * w,
+            self.center_y - self.vertical_distance * p.y * w,
         )
     }
 
+    /// Screen columns that are not yet fully covered by walls
+    pub fn h_open(&self) -> &[Range<i32>] {
+        &self.h_open
+    }
+
     pub fn is_complete(&self) -> bool {
         self.h_open.is_empty()
     }
 
     fn horizontally_mark_as_rendered(&mut self, r: Range<i32>) {
         let mut clipped = vec![];
-        let h_open = Rc::make_mut(&mut self.h_open);
 
-        for c in h_open.drain(..).into_iter() {
+        for c in self.h_open.drain(..) {
             let i = intersect(c.clone(), r.clone());
 
             if is_empty(&i) {
@@ -78,7 +254,7 @@ impl<'a> RenderingState<'a> {
             }
         }
 
-        *h_open = clipped;
+        self.h_open = clipped;
     }
 
     fn horizontally_clip(&self, r: Range<i32>) -> Vec<Range<i32>> {
@@ -95,12 +271,260 @@ impl<'a> RenderingState<'a> {
         to_render
     }
 
+    fn mark_plane(&mut self, plane: &Plane, x: i32, span: Range<i32>) {
+        if is_empty(&span) {
+            return;
+        }
+
+        let index = match self
+            .visplanes
+            .iter()
+            .position(|v| v.is_compatible(plane, x))
+        {
+            Some(index) => index,
+            None => {
+                self.visplanes.push(Visplane::new(plane, self.width));
+                self.visplanes.len() - 1
+            }
+        };
+
+        self.visplanes[index].columns[x as usize] = span;
+    }
+
+    fn mark_planes(&mut self, x: i32, top: i32, bottom: i32, floor: &Plane, ceil: &Plane) {
+        let v_open = self.v_open[x as usize].clone();
+
+        // Planes are only visible from one side. The sky is visible from anywhere
+        if ceil.height > 0. || ceil.is_sky() {
+            self.mark_plane(ceil, x, intersect(v_open.clone(), v_open.start..top));
+        }
+        if floor.height < 0. {
+            self.mark_plane(floor, x, intersect(v_open.clone(), bottom..v_open.end));
+        }
+    }
+
+    fn draw_sky(&mut self, visplane: &Visplane, sky: &Sprite, view_angle: f32) {
+        let colormap = self.colormap(0);
+
+        for (x, span) in visplane.columns.iter().enumerate() {
+            let angle = view_angle
+                + ((self.center_x - (x as f32 + 0.5)) / self.distance_to_projection_plane).atan();
+            let u =
+                ((angle / TAU * SKY_COLUMNS_PER_REVOLUTION) as i32).rem_euclid(sky.width() as i32);
+
+            for y in span.clone() {
+                let v = ((y as f32 + 0.5 - self.center_y) * VANILLA_PROJECTION
+                    / self.vertical_distance
+                    + SKY_TEXTURE_MID) as i32;
+                let v = v.rem_euclid(sky.height() as i32);
+
+                if let Some(pixel) = texel(sky, u as u32, v) {
+                    self.framebuffer[[y as usize, x]] = colormap[pixel as usize];
+                }
+            }
+        }
+    }
+
+    pub fn draw_planes(
+        &mut self,
+        flats: &FlatProvider,
+        sky: Option<&Sprite>,
+        pos: Vector2<f32>,
+        transform: Matrix2<f32>,
+    ) {
+        let view_to_world = transform.invert().unwrap();
+
+        let dir = view_to_world * vec2(0., 1.);
+        let view_angle = dir.y.atan2(dir.x);
+
+        for visplane in std::mem::replace(&mut self.visplanes, vec![]) {
+            if visplane.flat == SKY_FLAT {
+                if let Some(sky) = sky {
+                    self.draw_sky(&visplane, sky, view_angle);
+                }
+                continue;
+            }
+
+            let flat = match flats.get_flat(&visplane.flat) {
+                Some(flat) => flat,
+                None => continue,
+            };
+
+            let light_level = sector_light_level(visplane.light);
+
+            for (x, span) in visplane.columns.iter().enumerate() {
+                let view_x = x as f32 + 0.5 - self.center_x;
+
+                for y in span.clone() {
+                    // Distance to the plane along the view direction for this row
+                    let z = self.vertical_distance * visplane.height
+                        / (self.center_y - (y as f32 + 0.5));
+                    if !z.is_finite() {
+                        // The row is exactly on the horizon
+                        continue;
+                    }
+
+                    let view = vec2(view_x * z / self.distance_to_projection_plane, z);
+                    let world = pos + view_to_world * view - visplane.offset;
+
+                    let u = (world.x.floor() as i32) & (FLAT_SIZE as i32 - 1);
+                    let v = (-world.y.floor() as i32) & (FLAT_SIZE as i32 - 1);
+
+                    let colormap = self.colormap(plane_colormap(light_level, z));
+
+                    self.framebuffer[[y as usize, x]] =
+                        colormap[flat[v as usize * FLAT_SIZE + u as usize] as usize];
+                }
+            }
+        }
+    }
+
+    fn sprite_clip(&self, x: i32, scale: f32) -> Range<i32> {
+        let mut clip = 0..self.height;
+
+        for (wall_scale, open) in &self.silhouettes[x as usize] {
+            if *wall_scale > scale {
+                clip = intersect(clip, open.clone());
+            }
+        }
+
+        clip
+    }
+
+    /// Draw a column of a sprite or masked texture with its top at screen
+    /// row `top`, within the rows of `clip`
+    fn draw_masked_col(
+        &mut self,
+        x: i32,
+        top: f32,
+        scale: f32,
+        texture: &Sprite,
+        column: u32,
+        clip: Range<i32>,
+        colormap: &[u8],
+    ) {
+        put_scaled_column(
+            self.framebuffer,
+            x as usize,
+            top,
+            scale,
+            texture,
+            column,
+            clip,
+            |pixel| colormap[pixel as usize],
+        );
+    }
+
+    // The screen x of the left edge of the sprite, its horizontal scale and
+    // the screen columns it covers
+    fn sprite_placement(&self, vis_sprite: &VisSprite) -> (f32, f32, Range<i32>) {
+        let sprite = &vis_sprite.sprite;
+        let x_scale = vis_sprite.scale(self.distance_to_projection_plane);
+
+        let (_, left_offset) = sprite.origin();
+        let left_offset = if vis_sprite.flipped {
+            sprite.width() as f32 - left_offset as f32
+        } else {
+            left_offset as f32
+        };
+
+        let center = self.project(vec3(vis_sprite.pos.x, 0., vis_sprite.pos.y)).x;
+        let left = center - left_offset * x_scale;
+        let right = left + sprite.width() as f32 * x_scale;
+
+        let x_range = intersect(left.round() as i32..right.round() as i32, 0..self.width);
+
+        (left, x_scale, x_range)
+    }
+
+    fn draw_sprite(&mut self, vis_sprite: &VisSprite) {
+        let sprite = &vis_sprite.sprite;
+        let (left, x_scale, x_range) = self.sprite_placement(vis_sprite);
+        let scale = vis_sprite.scale(self.vertical_distance);
+        let colormap = self.scaled_colormap(vis_sprite.light_level, scale);
+
+        let (top_offset, _) = sprite.origin();
+        let top = self.center_y - (vis_sprite.bottom + top_offset as f32) * scale;
+
+        for x in x_range {
+            let column = ((x as f32 + 0.5 - left) / x_scale) as u32;
+            let column = std::cmp::min(column, sprite.width() as u32 - 1);
+            let column = if vis_sprite.flipped {
+                sprite.width() as u32 - 1 - column
+            } else {
+                column
+            };
+
+            let clip = self.sprite_clip(x, scale);
+            self.draw_masked_col(x, top, scale, sprite, column, clip, colormap);
+        }
+    }
+
+    fn draw_masked_wall_col(&mut self, column: &MaskedColumn, texture: &Sprite) {
+        let u = (column.u.round() as i32).rem_euclid(texture.width() as i32);
+        self.draw_masked_col(
+            column.x,
+            column.top,
+            column.scale,
+            texture,
+            u as u32,
+            column.clip.clone(),
+            column.colormap,
+        );
+    }
+
+    /// Draw the sprites, given back to front, along with the masked middle
+    /// textures. As in vanilla, a masked column is drawn just before the
+    /// first sprite in front of it, and the rest after all the sprites.
+    /// `texture` looks up a texture by name.
+    pub fn draw_masked<'t>(
+        &mut self,
+        vis_sprites: &[VisSprite],
+        texture: impl Fn(&[u8; 8]) -> Option<Sprite<'t>>,
+    ) {
+        let mut masked_walls = std::mem::take(&mut self.masked_walls);
+        let textures = masked_walls
+            .iter()
+            .map(|wall| texture(&wall.texture))
+            .collect::<Vec<_>>();
+
+        for vis_sprite in vis_sprites {
+            if vis_sprite.pos.y <= CLIP_NEAR {
+                continue;
+            }
+
+            let (_, _, x_range) = self.sprite_placement(vis_sprite);
+            let scale = vis_sprite.scale(self.vertical_distance);
+
+            // Back to front
+            for (wall, texture) in masked_walls.iter_mut().zip(&textures).rev() {
+                if let Some(texture) = texture {
+                    wall.columns.retain(|column| {
+                        let behind = x_range.contains(&column.x) && column.scale < scale;
+                        if behind {
+                            self.draw_masked_wall_col(column, texture);
+                        }
+                        !behind
+                    });
+                }
+            }
+
+            self.draw_sprite(vis_sprite);
+        }
+
+        for (wall, texture) in masked_walls.iter().zip(&textures).rev() {
+            if let Some(texture) = texture {
+                for column in &wall.columns {
+                    self.draw_masked_wall_col(column, texture);
+                }
+            }
+        }
+    }
+
     fn clip_near(
         a: Vector2<f32>,
         b: Vector2<f32>,
     ) -> Option<(Vector2<f32>, f32, Vector2<f32>, f32)> {
-        const CLIP_NEAR: f32 = 10.;
-
         if a.y <= CLIP_NEAR && b.y <= CLIP_NEAR {
             return None;
         }
@@ -126,46 +550,55 @@ impl<'a> RenderingState<'a> {
     fn draw_texture_col(
         &mut self,
         x: i32,
-        top: f32,
-        bottom: i32,
+        y_range: Range<i32>,
+        texture_top: f32,
         scale: f32,
         texture: &Sprite,
         column: u32,
+        colormap: &[u8],
     ) {
-        if bottom <= top as i32 {
+        // Vertical clipping
+        // let y_range = intersect(y_range, 0..self.height); // Redundant
+        let y_range = intersect(y_range, self.v_open[x as usize].clone());
+
+        let tile_height = texture.height() as f32 * scale;
+        if is_empty(&y_range) || tile_height <= 0. {
             return;
         }
 
-        for span in texture.col(column) {
-            let span_y_top = top + span.top as f32 * scale;
-            let span_y_bottom = top + scale * (span.top as u32 + span.pixels.len() as u32) as f32;
-            let dy = span_y_bottom - span_y_top;
+        // Start at the repetition of the texture that covers the top of the range
+        let skip = ((y_range.start as f32 - texture_top) / tile_height).floor();
+        let mut tile_top = texture_top + skip * tile_height;
 
-            let y_range =
-                span_y_top.round() as i32..std::cmp::min(span_y_bottom.round() as i32, bottom);
+        while tile_top < y_range.end as f32 {
+            for span in texture.col(column) {
+                let span_y_top = tile_top + span.top as f32 * scale;
+                let span_y_bottom =
+                    tile_top + scale * (span.top as u32 + span.pixels.len() as u32) as f32;
+                let dy = span_y_bottom - span_y_top;
 
-            // Vertical clipping
-            // let y_range = intersect(y_range, 0..200); // Redundant
-            let y_range = intersect(y_range, self.v_open[x as usize].clone());
+                let span_range = span_y_top.round() as i32..span_y_bottom.round() as i32;
 
-            for y in y_range {
-                let s = (y as f32 - span_y_top) / dy * span.pixels.len() as f32;
-                self.framebuffer[[y as usize, x as usize]] = span.pixels[s as usize];
+                for y in intersect(span_range, y_range.clone()) {
+                    let s = (y as f32 - span_y_top) / dy * span.pixels.len() as f32;
+                    let s = std::cmp::min(s as usize, span.pixels.len() - 1);
+                    self.framebuffer[[y as usize, x as usize]] = colormap[span.pixels[s] as usize];
+                }
             }
-        }
 
-        let rendered_to = top + texture.height() as f32 * scale;
-        if bottom >= rendered_to as i32 {
-            self.draw_texture_col(x, rendered_to, bottom, scale, texture, column);
+            tile_top += tile_height;
         }
     }
 
     pub fn wall(
         &mut self,
-        floor: f32,
-        ceil: f32,
+        floor: &Plane,
+        ceil: &Plane,
         a: Vector2<f32>,
         b: Vector2<f32>,
+        u_offset: f32,
+        light_level: i32,
+        texture_top: f32,
         texture: &Sprite,
     ) {
         let (a, ua, b, ub) = match Self::clip_near(a, b) {
@@ -176,16 +609,16 @@ impl<'a> RenderingState<'a> {
         let za = a.y;
         let zb = b.y;
 
-        let fa = self.project(vec3(a.x, floor, a.y));
-        let ca = self.project(vec3(a.x, ceil, a.y));
-        let fb = self.project(vec3(b.x, floor, b.y));
-        let cb = self.project(vec3(b.x, ceil, b.y));
+        let fa = self.project(vec3(a.x, floor.height, a.y));
+        let ca = self.project(vec3(a.x, ceil.height, a.y));
+        let fb = self.project(vec3(b.x, floor.height, b.y));
+        let cb = self.project(vec3(b.x, ceil.height, b.y));
 
         let d_ceil = cb - ca;
         let d_floor = fb - fa;
 
-        let scale_a = self.distance_to_projection_plane / a.y;
-        let scale_b = self.distance_to_projection_plane / b.y;
+        let scale_a = self.vertical_distance / a.y;
+        let scale_b = self.vertical_distance / b.y;
         let dscale = scale_b - scale_a;
 
         let x_range = fa.x.round() as i32..fb.x.round() as i32;
@@ -204,23 +637,41 @@ impl<'a> RenderingState<'a> {
             // Perspective correct interpolation of u coordinate
             // TODO: Derive from fundamental geometry
             let u = ((1. - t) * ua / za + t * ub / zb) / ((1. - t) / za + t / zb);
+            let u = u + u_offset;
 
             let u = (u.round() as i32).rem_euclid(texture.width() as i32);
 
-            self.draw_texture_col(x, top, bottom as _, scale_a + dscale * t, texture, u as u32);
+            let scale = scale_a + dscale * t;
+            let colormap = self.scaled_colormap(light_level, scale);
+
+            self.draw_texture_col(
+                x,
+                top.round() as i32..bottom as i32,
+                self.center_y - texture_top * scale,
+                scale,
+                texture,
+                u as u32,
+                colormap,
+            );
 
-            // TODO Yield visplanes
+            self.mark_planes(x, top.round() as _, bottom as _, floor, ceil);
+            self.silhouettes[x as usize].push((scale, 0..0));
         }
     }
 
     pub fn portal(
         &mut self,
+        front_floor: &Plane,
+        front_ceil: &Plane,
         floor: f32,
         ceil: f32,
         a: Vector2<f32>,
         b: Vector2<f32>,
-        upper: &Option<(f32, f32, Sprite)>,
-        lower: &Option<(f32, f32, Sprite)>,
+        u_offset: f32,
+        light_level: i32,
+        upper: &Option<WallSection>,
+        lower: &Option<WallSection>,
+        middle: &Option<MaskedSection>,
     ) {
         let (a, ua, b, ub) = match Self::clip_near(a, b) {
             None => return,
@@ -238,12 +689,13 @@ impl<'a> RenderingState<'a> {
         let d_ceil = cb - ca;
         let d_floor = fb - fa;
 
-        let scale_a = self.distance_to_projection_plane / a.y;
-        let scale_b = self.distance_to_projection_plane / b.y;
+        let scale_a = self.vertical_distance / a.y;
+        let scale_b = self.vertical_distance / b.y;
         let dscale = scale_b - scale_a;
 
         let x_range = fa.x.round() as i32..fb.x.round() as i32;
         let x_ranges = self.horizontally_clip(x_range);
+        let mut masked_columns = vec![];
 
         for x in x_ranges.into_iter().flatten() {
             let t = (x as f32 - fa.x) / d_floor.x;
@@ -253,41 +705,60 @@ impl<'a> RenderingState<'a> {
             let scale = scale_a + dscale * t;
 
             let u = ((1. - t) * ua / za + t * ub / zb) / ((1. - t) / za + t / zb);
+            let colormap = self.scaled_colormap(light_level, scale);
+
+            self.mark_planes(
+                x,
+                (self.center_y - front_ceil.height * scale).round() as _,
+                (self.center_y - front_floor.height * scale).round() as _,
+                front_floor,
+                front_ceil,
+            );
 
-            if let Some((top, bottom, texture)) = upper {
-                let u = (u.round() as i32).rem_euclid(texture.width() as i32);
-                self.draw_texture_col(
-                    x,
-                    100. - top * scale,
-                    (100. - bottom * scale) as _,
-                    scale,
-                    texture,
-                    u as u32,
-                );
-            }
-
-            if let Some((top, bottom, texture)) = lower {
-                let u = (u.round() as i32).rem_euclid(texture.width() as i32);
+            for section in upper.iter().chain(lower.iter()) {
+                let texture = &section.texture;
+                let u = ((u + u_offset).round() as i32).rem_euclid(texture.width() as i32);
                 self.draw_texture_col(
                     x,
-                    100. - top * scale,
-                    (100. - bottom * scale) as _,
+                    (self.center_y - section.top * scale).round() as i32
+                        ..(self.center_y - section.bottom * scale) as i32,
+                    self.center_y - section.texture_top * scale,
                     scale,
                     texture,
                     u as u32,
+                    colormap,
                 );
             }
 
-            // TODO Yield visplanes
-
             let v_clipped = intersect(
                 self.v_open[x as usize].clone(),
                 top.round() as _..bottom.round() as _,
             );
 
+            if let Some(middle) = middle {
+                masked_columns.push(MaskedColumn {
+                    x,
+                    scale,
+                    u: u + u_offset,
+                    top: self.center_y - middle.texture_top * scale,
+                    clip: v_clipped.clone(),
+                    colormap,
+                });
+            }
+
             if v_clipped != self.v_open[x as usize] {
-                let v_open = Rc::make_mut(&mut self.v_open);
-                v_open[x as usize] = v_clipped;
+                self.silhouettes[x as usize].push((scale, v_clipped.clone()));
+
+                self.v_open[x as usize] = v_clipped;
+            }
+        }
+
+        if let Some(middle) = middle {
+            if !masked_columns.is_empty() {
+                self.masked_walls.push(MaskedWall {
+                    texture: middle.texture,
+                    columns: masked_columns,
+                });
             }
         }
     }
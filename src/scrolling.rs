@@ -0,0 +1,176 @@
+//! Scrolling walls and flats, following vanilla's special 48 and the
+//! scrollers of Boom's `P_SpawnScrollers`. Only scrollers with a constant
+//! speed are supported. The ones driven by moving sectors stand still, as
+//! nothing moves in the renderer.
+
+use cgmath::prelude::*;
+use cgmath::{vec2, Vector2};
+use std::collections::HashMap;
+
+// Linedef specials
+const SCROLL_LEFT: u16 = 48;
+const SCROLL_RIGHT: u16 = 85;
+const SCROLL_CEILING: u16 = 250;
+const SCROLL_FLOOR: u16 = 251;
+const SCROLL_FLOOR_AND_CARRY: u16 = 253;
+const SCROLL_TAGGED_WALLS: u16 = 254;
+const SCROLL_BY_OFFSETS: u16 = 255;
+
+// Boom scrollers move by the vector of their line divided by this each tic
+const SCROLL_DIVISOR: f32 = 32.;
+
+fn vertex(map: &wad_map::Map, index: u16) -> Vector2<f32> {
+    let v = &map.vertexes[index as usize];
+    vec2(v.x as f32, v.y as f32)
+}
+
+fn add(speeds: &mut HashMap<u16, Vector2<f32>>, id: u16, speed: Vector2<f32>) {
+    *speeds.entry(id).or_insert_with(Vector2::zero) += speed;
+}
+
+fn offset(speeds: &HashMap<u16, Vector2<f32>>, id: u16, tic: u32) -> Vector2<f32> {
+    speeds
+        .get(&id)
+        .map_or_else(Vector2::zero, |&speed| speed * tic as f32)
+}
+
+/// The speeds, in map units per tic, of everything that scrolls in a map.
+/// Several scrollers acting on the same wall or flat add up.
+#[derive(Default)]
+pub struct Scrollers {
+    // Change of the texture offsets of sidedefs
+    sides: HashMap<u16, Vector2<f32>>,
+    // Movement of the flats of sectors
+    floors: HashMap<u16, Vector2<f32>>,
+    ceilings: HashMap<u16, Vector2<f32>>,
+}
+
+impl Scrollers {
+    pub fn new(map: &wad_map::Map) -> Scrollers {
+        let mut scrollers = Scrollers::default();
+
+        let tagged_sectors = |tag: u16| {
+            map.sectors
+                .iter()
+                .enumerate()
+                .filter(move |(_, sector)| sector.tag == tag)
+                .map(|(i, _)| i as u16)
+        };
+
+        for (i, linedef) in map.linedefs.iter().enumerate() {
+            let speed = (vertex(map, linedef.b) - vertex(map, linedef.a)) / SCROLL_DIVISOR;
+
+            match linedef.special_type {
+                SCROLL_LEFT | SCROLL_RIGHT => {
+                    if let Some(side) = linedef.right_sidedef {
+                        let direction = if linedef.special_type == SCROLL_LEFT {
+                            1.
+                        } else {
+                            -1.
+                        };
+                        add(&mut scrollers.sides, side, vec2(direction, 0.));
+                    }
+                }
+                SCROLL_BY_OFFSETS => {
+                    if let Some(side) = linedef.right_sidedef {
+                        let sidedef = &map.sidedefs[side as usize];
+                        let speed = vec2(-sidedef.x_offset as f32, sidedef.y_offset as f32);
+                        add(&mut scrollers.sides, side, speed);
+                    }
+                }
+                SCROLL_TAGGED_WALLS => {
+                    let tagged = map
+                        .linedefs
+                        .iter()
+                        .enumerate()
+                        .filter(|&(j, other)| j != i && other.sector_tag == linedef.sector_tag);
+
+                    for (_, other) in tagged {
+                        let side = match other.right_sidedef {
+                            Some(side) => side,
+                            None => continue,
+                        };
+
+                        // The part of the movement along the wall scrolls
+                        // it sideways, and the rest up or down
+                        let along = vertex(map, other.b) - vertex(map, other.a);
+                        let length = along.magnitude();
+                        if length == 0. {
+                            continue;
+                        }
+
+                        let speed = vec2(-speed.dot(along), -speed.perp_dot(along)) / length;
+                        add(&mut scrollers.sides, side, speed);
+                    }
+                }
+                SCROLL_CEILING => {
+                    for sector in tagged_sectors(linedef.sector_tag) {
+                        add(&mut scrollers.ceilings, sector, speed);
+                    }
+                }
+                SCROLL_FLOOR | SCROLL_FLOOR_AND_CARRY => {
+                    for sector in tagged_sectors(linedef.sector_tag) {
+                        add(&mut scrollers.floors, sector, speed);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        scrollers
+    }
+
+    /// How much the horizontal and vertical texture offsets of the sidedef
+    /// have changed at the given tic
+    pub fn side_offset(&self, sidedef: u16, tic: u32) -> Vector2<f32> {
+        offset(&self.sides, sidedef, tic)
+    }
+
+    /// How far the floor flat of the sector has moved at the given tic
+    pub fn floor_offset(&self, sector: u16, tic: u32) -> Vector2<f32> {
+        offset(&self.floors, sector, tic)
+    }
+
+    /// How far the ceiling flat of the sector has moved at the given tic
+    pub fn ceil_offset(&self, sector: u16, tic: u32) -> Vector2<f32> {
+        offset(&self.ceilings, sector, tic)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::*;
+
+    #[test]
+    fn scrollers() {
+        let s = ROOM_SIZE as f32;
+
+        let map = MapData {
+            // The west wall scrolls, the north wall moves the floor and the
+            // east wall scrolls the south wall with its own vector
+            line_specials: vec![
+                (SCROLL_LEFT, 0),
+                (SCROLL_FLOOR, 1),
+                (SCROLL_TAGGED_WALLS, 2),
+                (0, 2),
+            ],
+            sector_tags: vec![1],
+            ..room()
+        };
+        let wad = map.add_to(resources(), "E1M1").parse();
+        let map = wad_map::read_map(&wad.as_slice(), "E1M1").unwrap();
+        let scrollers = Scrollers::new(&map);
+
+        assert_eq!(scrollers.side_offset(0, 35), vec2(35., 0.));
+        assert_eq!(scrollers.side_offset(0, 0), vec2(0., 0.));
+        assert_eq!(scrollers.floor_offset(0, 35), vec2(35. * s / 32., 0.));
+        assert_eq!(scrollers.ceil_offset(0, 35), vec2(0., 0.));
+
+        // The east wall points south, across the south wall, which scrolls
+        // it vertically
+        assert_eq!(scrollers.side_offset(3, 35), vec2(0., 35. * s / 32.));
+        assert_eq!(scrollers.side_offset(2, 35), vec2(0., 0.));
+        assert_eq!(scrollers.side_offset(1, 35), vec2(0., 0.));
+    }
+}
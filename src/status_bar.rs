@@ -0,0 +1,409 @@
+//! The vanilla status bar, composed as `ST_Drawer` does from the STBAR
+//! background and the status bar fonts, keys and faces
+
+use crate::error::Error;
+use crate::rendering_state::VANILLA_PIXEL_ASPECT;
+use crate::util::*;
+use crate::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use ndarray::prelude::*;
+use std::str::FromStr;
+use wad_gfx::Sprite;
+
+/// Height of the status bar in vanilla, which leaves 168 rows for the view
+pub const STATUS_BAR_HEIGHT: usize = 32;
+
+// Positions of the widgets relative to the top left of the status bar. Big
+// numbers are right aligned to end at their x.
+const AMMO_X: i16 = 44;
+const HEALTH_X: i16 = 90;
+const ARMOR_X: i16 = 221;
+const BIG_NUMBER_Y: i16 = 3;
+
+const ARMS_BACKGROUND_X: i16 = 104;
+const ARMS_X: i16 = 111;
+const ARMS_Y: i16 = 4;
+const ARMS_X_SPACE: i16 = 12;
+const ARMS_Y_SPACE: i16 = 10;
+
+const FACE_X: i16 = 143;
+
+const KEYS_X: i16 = 239;
+const KEY_YS: [i16; 3] = [3, 13, 23];
+
+const AMMO_COUNT_X: i16 = 288;
+const MAX_AMMO_X: i16 = 314;
+const AMMO_COUNT_YS: [i16; 4] = [5, 11, 17, 23];
+
+// Space between the minus sign and the number it precedes
+const MINUS_WIDTH: i16 = 8;
+
+// Faces get more hurt in this many steps as health goes from 100 to 0
+const PAIN_LEVELS: i32 = 5;
+
+/// The expression of the face in the middle of the status bar
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Face {
+    /// Looking ahead for 0, and to either side for 1 and 2, as the face
+    /// glances around
+    Straight(u8),
+    TurnLeft,
+    TurnRight,
+    Ouch,
+    Evil,
+    Rampage,
+    God,
+    Dead,
+}
+
+impl Face {
+    /// 0 to 2 are the straight faces, followed by the others in order
+    pub fn from_code(code: i32) -> Option<Face> {
+        match code {
+            0..=2 => Some(Face::Straight(code as u8)),
+            3 => Some(Face::TurnLeft),
+            4 => Some(Face::TurnRight),
+            5 => Some(Face::Ouch),
+            6 => Some(Face::Evil),
+            7 => Some(Face::Rampage),
+            8 => Some(Face::God),
+            9 => Some(Face::Dead),
+            _ => None,
+        }
+    }
+
+    // The face lump, which looks more hurt at lower health
+    fn lump_name(self, health: i32) -> String {
+        let pain = (100 - health.max(0).min(100)) * PAIN_LEVELS / 101;
+
+        match self {
+            Face::Straight(glance) => format!("STFST{}{}", pain, glance.min(2)),
+            Face::TurnLeft => format!("STFTL{}0", pain),
+            Face::TurnRight => format!("STFTR{}0", pain),
+            Face::Ouch => format!("STFOUCH{}", pain),
+            Face::Evil => format!("STFEVL{}", pain),
+            Face::Rampage => format!("STFKILL{}", pain),
+            Face::God => "STFGOD0".to_owned(),
+            Face::Dead => "STFDEAD0".to_owned(),
+        }
+    }
+}
+
+impl FromStr for Face {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Face, String> {
+        match s {
+            "straight" => Ok(Face::Straight(0)),
+            "left" => Ok(Face::TurnLeft),
+            "right" => Ok(Face::TurnRight),
+            "ouch" => Ok(Face::Ouch),
+            "evil" => Ok(Face::Evil),
+            "rampage" => Ok(Face::Rampage),
+            "god" => Ok(Face::God),
+            "dead" => Ok(Face::Dead),
+            _ => Err("Expected straight, left, right, ouch, evil, rampage, god or dead".to_owned()),
+        }
+    }
+}
+
+/// What the status bar shows
+#[derive(Clone, Debug, PartialEq)]
+pub struct StatusBar {
+    pub health: i32,
+    pub armor: i32,
+
+    /// Ammo of the weapon in hand, or `None` for weapons that need none
+    pub ammo: Option<i32>,
+
+    /// Whether the weapons in slots 2 to 7 are owned
+    pub arms: [bool; 6],
+
+    /// Blue, yellow and red keycards, then blue, yellow and red skull keys
+    pub keys: [bool; 6],
+
+    /// Current and maximum bullets, shells, rockets and cells
+    pub ammo_counts: [(i32, i32); 4],
+
+    pub face: Face,
+}
+
+/// Rows of a screen of the given height taken by the status bar
+pub fn status_bar_rows(height: usize) -> usize {
+    (height * STATUS_BAR_HEIGHT + SCREEN_HEIGHT / 2) / SCREEN_HEIGHT
+}
+
+fn sprite<'a>(wad: &wad::WadSlice<'a>, name: &str) -> Result<Sprite<'a>, Error> {
+    required_patch(wad, name)
+}
+
+// Draw a number with its last digit ending at x, as `STlib_drawNum`. Only the
+// last `digits` digits are shown, and negative numbers are limited to what
+// fits next to the minus sign.
+fn draw_number(
+    bar: &mut ArrayViewMut2<u8>,
+    wad: &wad::WadSlice,
+    font: &str,
+    x: i16,
+    y: i16,
+    value: i32,
+    digits: u32,
+) -> Result<(), Error> {
+    let zero = sprite(wad, &format!("{}0", font))?;
+    let width = zero.width() as i16;
+
+    let negative = value < 0;
+    let mut value = if negative {
+        (-(value as i64)).min(10i64.pow(digits - 1) - 1) as i32
+    } else {
+        value
+    };
+
+    let mut x = x;
+    if value == 0 {
+        put_sprite(bar, x - width, y, &zero);
+    }
+
+    for _ in 0..digits {
+        if value == 0 {
+            break;
+        }
+        x -= width;
+        put_sprite(bar, x, y, &sprite(wad, &format!("{}{}", font, value % 10))?);
+        value /= 10;
+    }
+
+    if negative {
+        put_sprite(bar, x - MINUS_WIDTH, y, &sprite(wad, "STTMINUS")?);
+    }
+
+    Ok(())
+}
+
+impl StatusBar {
+    /// Draw the status bar at vanilla resolution into `bar`, which is 320 by
+    /// 32 pixels
+    fn compose(&self, wad: &wad::WadSlice, bar: &mut ArrayViewMut2<u8>) -> Result<(), Error> {
+        put_sprite(bar, 0, 0, &sprite(wad, "STBAR")?);
+
+        if let Some(ammo) = self.ammo {
+            draw_number(bar, wad, "STTNUM", AMMO_X, BIG_NUMBER_Y, ammo, 3)?;
+        }
+
+        let percent = sprite(wad, "STTPRCNT")?;
+        for &(x, value) in &[(HEALTH_X, self.health), (ARMOR_X, self.armor)] {
+            draw_number(bar, wad, "STTNUM", x, BIG_NUMBER_Y, value, 3)?;
+            put_sprite(bar, x, BIG_NUMBER_Y, &percent);
+        }
+
+        put_sprite(bar, ARMS_BACKGROUND_X, 0, &sprite(wad, "STARMS")?);
+        for (i, &owned) in self.arms.iter().enumerate() {
+            let font = if owned { "STYSNUM" } else { "STGNUM" };
+            let x = ARMS_X + (i % 3) as i16 * ARMS_X_SPACE;
+            let y = ARMS_Y + (i / 3) as i16 * ARMS_Y_SPACE;
+            put_sprite(bar, x, y, &sprite(wad, &format!("{}{}", font, i + 2))?);
+        }
+
+        let face = self.face.lump_name(self.health);
+        put_sprite(bar, FACE_X, 0, &sprite(wad, &face)?);
+
+        // Skull keys take the place of keycards of the same color
+        for (i, &y) in KEY_YS.iter().enumerate() {
+            let key = if self.keys[i + 3] {
+                Some(i + 3)
+            } else if self.keys[i] {
+                Some(i)
+            } else {
+                None
+            };
+
+            if let Some(key) = key {
+                put_sprite(bar, KEYS_X, y, &sprite(wad, &format!("STKEYS{}", key))?);
+            }
+        }
+
+        for (&(count, max), &y) in self.ammo_counts.iter().zip(&AMMO_COUNT_YS) {
+            draw_number(bar, wad, "STYSNUM", AMMO_COUNT_X, y, count, 3)?;
+            draw_number(bar, wad, "STYSNUM", MAX_AMMO_X, y, max, 3)?;
+        }
+
+        Ok(())
+    }
+
+    /// Draw the status bar to fill the height of `screen`, centered and
+    /// keeping its shape on pixels `pixel_aspect` times taller than wide
+    pub fn draw(
+        &self,
+        wad: &wad::WadSlice,
+        screen: &mut ArrayViewMut2<u8>,
+        pixel_aspect: f32,
+    ) -> Result<(), Error> {
+        let mut buf = vec![0; SCREEN_WIDTH * STATUS_BAR_HEIGHT];
+        let mut bar =
+            ArrayViewMut2::from_shape((STATUS_BAR_HEIGHT, SCREEN_WIDTH), &mut buf[..]).unwrap();
+        self.compose(wad, &mut bar)?;
+
+        let (height, width) = screen.dim();
+        let y_scale = height as f32 / STATUS_BAR_HEIGHT as f32;
+        let x_scale = y_scale * pixel_aspect / VANILLA_PIXEL_ASPECT;
+        let left = (width as f32 - SCREEN_WIDTH as f32 * x_scale) / 2.;
+
+        for y in 0..height {
+            let v = ((y as f32 + 0.5) / y_scale) as usize;
+            let v = v.min(STATUS_BAR_HEIGHT - 1);
+
+            for x in 0..width {
+                let u = ((x as f32 + 0.5 - left) / x_scale).floor();
+                screen[[y, x]] = if u >= 0. && u < SCREEN_WIDTH as f32 {
+                    bar[[v, u as usize]]
+                } else {
+                    0
+                };
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::*;
+
+    fn status_bar() -> StatusBar {
+        StatusBar {
+            health: 100,
+            armor: 0,
+            ammo: Some(50),
+            arms: [true, false, false, false, false, false],
+            keys: [false; 6],
+            ammo_counts: [(50, 200), (0, 50), (0, 50), (0, 300)],
+            face: Face::Straight(0),
+        }
+    }
+
+    // Every status bar lump as a solid patch, in a color of its own
+    fn status_bar_wad() -> wad::Wad {
+        let mut lumps = vec![
+            ("STBAR".to_owned(), patch(320, 32, 1)),
+            ("STARMS".to_owned(), patch(40, 32, 2)),
+            ("STTPRCNT".to_owned(), patch(14, 16, 3)),
+            ("STTMINUS".to_owned(), patch(6, 16, 4)),
+        ];
+        for digit in 0..10 {
+            lumps.push((format!("STTNUM{}", digit), patch(14, 16, 10 + digit)));
+            lumps.push((format!("STYSNUM{}", digit), patch(4, 6, 20 + digit)));
+            lumps.push((format!("STGNUM{}", digit), patch(4, 6, 30 + digit)));
+        }
+        for key in 0..6 {
+            lumps.push((format!("STKEYS{}", key), patch(7, 7, 40 + key)));
+        }
+        for pain in 0..5 {
+            lumps.push((format!("STFST{}0", pain), patch(24, 29, 50 + pain)));
+        }
+        lumps.push(("STFDEAD0".to_owned(), patch(24, 29, 60)));
+
+        lumps
+            .into_iter()
+            .fold(WadBuilder::new(), |wad, (name, lump)| wad.lump(&name, lump))
+            .parse()
+    }
+
+    fn compose(status_bar: &StatusBar) -> Vec<u8> {
+        let wad = status_bar_wad();
+        let mut buf = vec![0; SCREEN_WIDTH * STATUS_BAR_HEIGHT];
+        let mut bar =
+            ArrayViewMut2::from_shape((STATUS_BAR_HEIGHT, SCREEN_WIDTH), &mut buf[..]).unwrap();
+        status_bar.compose(&wad.as_slice(), &mut bar).unwrap();
+        buf
+    }
+
+    #[test]
+    fn faces() {
+        assert_eq!(Face::Straight(1).lump_name(100), "STFST01");
+        assert_eq!(Face::Straight(5).lump_name(100), "STFST02");
+        assert_eq!(Face::TurnLeft.lump_name(60), "STFTL10");
+        assert_eq!(Face::Ouch.lump_name(1), "STFOUCH4");
+        assert_eq!(Face::Evil.lump_name(-20), "STFEVL4");
+        assert_eq!(Face::Rampage.lump_name(200), "STFKILL0");
+        assert_eq!(Face::Dead.lump_name(0), "STFDEAD0");
+
+        assert_eq!(Face::from_code(2), Some(Face::Straight(2)));
+        assert_eq!(Face::from_code(9), Some(Face::Dead));
+        assert_eq!(Face::from_code(10), None);
+        assert_eq!("god".parse::<Face>(), Ok(Face::God));
+    }
+
+    #[test]
+    fn widgets() {
+        let mut status_bar = status_bar();
+        status_bar.health = 7;
+        status_bar.armor = -120;
+        status_bar.keys = [true, true, false, false, true, true];
+
+        let buf = compose(&status_bar);
+        let pixel = |x: usize, y: usize| buf[y * SCREEN_WIDTH + x];
+
+        assert_eq!(pixel(0, 0), 1);
+
+        // Ammo 50 ends at x 44, and health 7 at x 90 followed by percent
+        assert_eq!(pixel(16, 3), 15);
+        assert_eq!(pixel(30, 3), 10);
+        assert_eq!(pixel(44, 3), 1);
+        assert_eq!(pixel(76, 3), 17);
+        assert_eq!(pixel(62, 3), 1);
+        assert_eq!(pixel(90, 3), 3);
+
+        // Armor is limited to -99
+        assert_eq!(pixel(193, 3), 19);
+        assert_eq!(pixel(207, 3), 19);
+        assert_eq!(pixel(187, 3), 4);
+
+        // The pistol is owned, the shotgun is not
+        assert_eq!(pixel(111, 4), 22);
+        assert_eq!(pixel(123, 4), 33);
+        assert_eq!(pixel(105, 0), 2);
+
+        // Hurt face
+        assert_eq!(pixel(143, 0), 54);
+
+        // Blue keycard, yellow skull key and red skull key
+        assert_eq!(pixel(239, 3), 40);
+        assert_eq!(pixel(239, 13), 44);
+        assert_eq!(pixel(239, 23), 45);
+
+        // 50 of 200 bullets
+        assert_eq!(pixel(280, 5), 25);
+        assert_eq!(pixel(284, 5), 20);
+        assert_eq!(pixel(302, 5), 22);
+    }
+
+    #[test]
+    fn scaling() {
+        let wad = status_bar_wad();
+        let status_bar = status_bar();
+        let vanilla = compose(&status_bar);
+
+        // Twice the size on a 16:9 screen with square pixels, centered
+        let (width, height) = (1280, 64);
+        let mut buf = vec![0xff; width * height];
+        let mut screen = ArrayViewMut2::from_shape((height, width), &mut buf[..]).unwrap();
+        status_bar.draw(&wad.as_slice(), &mut screen, 1.).unwrap();
+
+        let x_scale = 2. / VANILLA_PIXEL_ASPECT;
+        let left = (width as f32 - 320. * x_scale) / 2.;
+        for &(x, y) in &[(0, 0), (16, 3), (143, 0), (288, 23)] {
+            let screen_x = (left + (x as f32 + 0.5) * x_scale) as usize;
+            assert_eq!(
+                buf[(y * 2 + 1) * width + screen_x],
+                vanilla[y * SCREEN_WIDTH + x]
+            );
+        }
+        assert_eq!(buf[0], 0);
+        assert_eq!(buf[width - 1], 0);
+
+        assert_eq!(status_bar_rows(200), 32);
+        assert_eq!(status_bar_rows(400), 64);
+        assert_eq!(status_bar_rows(1080), 173);
+    }
+}
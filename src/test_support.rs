@@ -0,0 +1,461 @@
+//! Tiny WADs built in code, so tests can run without doom1.wad
+
+use std::f32::consts::PI;
+
+pub const WALL_COLOR: u8 = 0x40;
+pub const FLOOR_COLOR: u8 = 0x50;
+pub const CEIL_COLOR: u8 = 0x60;
+
+pub const ROOM_SIZE: i16 = 256;
+pub const ROOM_HEIGHT: i16 = 128;
+
+fn name(name: &str) -> [u8; 8] {
+    let mut id = [0u8; 8];
+    id[..name.len()].copy_from_slice(name.as_bytes());
+    id
+}
+
+fn put_i16(buf: &mut Vec<u8>, x: i16) {
+    buf.extend_from_slice(&x.to_le_bytes());
+}
+
+fn put_u16(buf: &mut Vec<u8>, x: u16) {
+    buf.extend_from_slice(&x.to_le_bytes());
+}
+
+fn put_i32(buf: &mut Vec<u8>, x: i32) {
+    buf.extend_from_slice(&x.to_le_bytes());
+}
+
+fn put_name(buf: &mut Vec<u8>, x: &str) {
+    buf.extend_from_slice(&name(x));
+}
+
+#[derive(Default)]
+pub struct WadBuilder {
+    lumps: Vec<([u8; 8], Vec<u8>)>,
+}
+
+impl WadBuilder {
+    pub fn new() -> WadBuilder {
+        Default::default()
+    }
+
+    pub fn lump(mut self, lump_name: &str, data: Vec<u8>) -> WadBuilder {
+        self.lumps.push((name(lump_name), data));
+        self
+    }
+
+    pub fn marker(self, lump_name: &str) -> WadBuilder {
+        self.lump(lump_name, vec![])
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let data_size: usize = self.lumps.iter().map(|(_, data)| data.len()).sum();
+
+        let mut buf = b"IWAD".to_vec();
+        put_i32(&mut buf, self.lumps.len() as i32);
+        put_i32(&mut buf, 12 + data_size as i32);
+
+        for (_, data) in &self.lumps {
+            buf.extend_from_slice(data);
+        }
+
+        let mut pos = 12;
+        for (lump_name, data) in &self.lumps {
+            put_i32(&mut buf, pos as i32);
+            put_i32(&mut buf, data.len() as i32);
+            buf.extend_from_slice(lump_name);
+            pos += data.len();
+        }
+
+        buf
+    }
+
+    pub fn parse(&self) -> wad::Wad {
+        wad::parse_wad(self.build()).unwrap()
+    }
+}
+
+/// Grayscale palettes. Each of the 14 is brighter than the one before, so
+/// tests can tell them apart.
+pub fn playpal() -> Vec<u8> {
+    (0..14)
+        .flat_map(|palette| (0..768).map(move |i| ((i / 3 + palette * 16) % 256) as u8))
+        .collect()
+}
+
+/// Colormaps that leave every color unchanged, so rendered output does not
+/// depend on lighting
+pub fn colormap() -> Vec<u8> {
+    (0..34 * 256).map(|i| (i % 256) as u8).collect()
+}
+
+/// A patch in picture format filled with a single color
+pub fn patch(width: u16, height: u8, color: u8) -> Vec<u8> {
+    let header_size = 8 + 4 * width as usize;
+    let column_size = 5 + height as usize;
+
+    let mut buf = vec![];
+    put_u16(&mut buf, width);
+    put_u16(&mut buf, height as u16);
+    put_i16(&mut buf, 0);
+    put_i16(&mut buf, 0);
+
+    for x in 0..width as usize {
+        put_i32(&mut buf, (header_size + x * column_size) as i32);
+    }
+
+    for _ in 0..width {
+        buf.extend_from_slice(&[0, height, 0]);
+        buf.extend(std::iter::repeat(color).take(height as usize));
+        buf.extend_from_slice(&[0, 0xff]);
+    }
+
+    buf
+}
+
+pub fn pnames(names: &[&str]) -> Vec<u8> {
+    let mut buf = vec![];
+    put_i32(&mut buf, names.len() as i32);
+    for x in names {
+        put_name(&mut buf, x);
+    }
+    buf
+}
+
+/// A texture directory where each texture consists of a single patch, given
+/// as (name, width, height, patch index)
+pub fn texture_directory(textures: &[(&str, i16, i16, i16)]) -> Vec<u8> {
+    const TEXTURE_SIZE: usize = 22 + 10;
+
+    let mut buf = vec![];
+    put_i32(&mut buf, textures.len() as i32);
+    for i in 0..textures.len() {
+        put_i32(&mut buf, (4 + 4 * textures.len() + i * TEXTURE_SIZE) as i32);
+    }
+
+    for &(texture_name, width, height, patch) in textures {
+        put_name(&mut buf, texture_name);
+        put_i32(&mut buf, 0);
+        put_i16(&mut buf, width);
+        put_i16(&mut buf, height);
+        put_i32(&mut buf, 0);
+        put_i16(&mut buf, 1);
+
+        put_i16(&mut buf, 0);
+        put_i16(&mut buf, 0);
+        put_i16(&mut buf, patch);
+        put_i16(&mut buf, 1);
+        put_i16(&mut buf, 0);
+    }
+
+    buf
+}
+
+pub fn flat(color: u8) -> Vec<u8> {
+    vec![color; 64 * 64]
+}
+
+pub struct Node {
+    pub partition: (i16, i16, i16, i16),
+    pub right_bbox: (i16, i16, i16, i16),
+    pub left_bbox: (i16, i16, i16, i16),
+    pub right_child: u16,
+    pub left_child: u16,
+}
+
+pub const SUBSECTOR: u16 = 0x8000;
+
+/// The lumps of a map in a readable form. Bounding boxes are given as
+/// (top, bottom, left, right), as they are stored in the WAD.
+#[derive(Default)]
+pub struct MapData {
+    /// (x, y, angle, type)
+    pub things: Vec<(i16, i16, i16, u16)>,
+
+    pub vertexes: Vec<(i16, i16)>,
+
+    /// (start vertex, end vertex, flags, right sidedef, left sidedef)
+    pub linedefs: Vec<(u16, u16, u16, Option<u16>, Option<u16>)>,
+
+    /// (special, tag) of the first linedefs. The rest have neither.
+    pub line_specials: Vec<(u16, u16)>,
+
+    /// (upper texture, lower texture, middle texture, sector)
+    pub sidedefs: Vec<(&'static str, &'static str, &'static str, u16)>,
+
+    /// (start vertex, end vertex, linedef, reversed, offset)
+    pub segs: Vec<(u16, u16, u16, bool, i16)>,
+
+    /// (seg count, first seg)
+    pub subsectors: Vec<(u16, u16)>,
+
+    pub nodes: Vec<Node>,
+
+    /// (floor height, ceiling height, floor flat, ceiling flat, light)
+    pub sectors: Vec<(i16, i16, &'static str, &'static str, i16)>,
+
+    /// Tags of the first sectors. The rest are untagged.
+    pub sector_tags: Vec<u16>,
+}
+
+impl MapData {
+    fn things_lump(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        for &(x, y, angle, thing_type) in &self.things {
+            put_i16(&mut buf, x);
+            put_i16(&mut buf, y);
+            put_i16(&mut buf, angle);
+            put_u16(&mut buf, thing_type);
+            put_u16(&mut buf, 0x0007);
+        }
+        buf
+    }
+
+    fn linedefs_lump(&self) -> Vec<u8> {
+        let sidedef = |x: Option<u16>| x.unwrap_or(0xffff);
+
+        let mut buf = vec![];
+        for (i, &(a, b, flags, right, left)) in self.linedefs.iter().enumerate() {
+            let (special, tag) = self.line_specials.get(i).copied().unwrap_or((0, 0));
+
+            put_u16(&mut buf, a);
+            put_u16(&mut buf, b);
+            put_u16(&mut buf, flags);
+            put_u16(&mut buf, special);
+            put_u16(&mut buf, tag);
+            put_u16(&mut buf, sidedef(right));
+            put_u16(&mut buf, sidedef(left));
+        }
+        buf
+    }
+
+    fn sidedefs_lump(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        for &(upper, lower, middle, sector) in &self.sidedefs {
+            put_i16(&mut buf, 0);
+            put_i16(&mut buf, 0);
+            put_name(&mut buf, upper);
+            put_name(&mut buf, lower);
+            put_name(&mut buf, middle);
+            put_u16(&mut buf, sector);
+        }
+        buf
+    }
+
+    fn vertexes_lump(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        for &(x, y) in &self.vertexes {
+            put_i16(&mut buf, x);
+            put_i16(&mut buf, y);
+        }
+        buf
+    }
+
+    fn segs_lump(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        for &(a, b, linedef, reversed, offset) in &self.segs {
+            let (ax, ay) = self.vertexes[a as usize];
+            let (bx, by) = self.vertexes[b as usize];
+            let angle = ((by - ay) as f32).atan2((bx - ax) as f32);
+            let angle = (angle / (2. * PI) * 65536.) as i32 as u16;
+
+            put_u16(&mut buf, a);
+            put_u16(&mut buf, b);
+            put_u16(&mut buf, angle);
+            put_u16(&mut buf, linedef);
+            put_u16(&mut buf, reversed as u16);
+            put_i16(&mut buf, offset);
+        }
+        buf
+    }
+
+    fn subsectors_lump(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        for &(count, first) in &self.subsectors {
+            put_u16(&mut buf, count);
+            put_u16(&mut buf, first);
+        }
+        buf
+    }
+
+    fn nodes_lump(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        for node in &self.nodes {
+            let (x, y, dx, dy) = node.partition;
+            for &v in &[x, y, dx, dy] {
+                put_i16(&mut buf, v);
+            }
+            for &(top, bottom, left, right) in &[node.right_bbox, node.left_bbox] {
+                for &v in &[top, bottom, left, right] {
+                    put_i16(&mut buf, v);
+                }
+            }
+            put_u16(&mut buf, node.right_child);
+            put_u16(&mut buf, node.left_child);
+        }
+        buf
+    }
+
+    fn sectors_lump(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        for (i, &(floor, ceil, floor_flat, ceil_flat, light)) in self.sectors.iter().enumerate() {
+            put_i16(&mut buf, floor);
+            put_i16(&mut buf, ceil);
+            put_name(&mut buf, floor_flat);
+            put_name(&mut buf, ceil_flat);
+            put_i16(&mut buf, light);
+            put_i16(&mut buf, 0);
+            put_u16(&mut buf, self.sector_tags.get(i).copied().unwrap_or(0));
+        }
+        buf
+    }
+
+    fn reject_lump(&self) -> Vec<u8> {
+        let sectors = self.sectors.len();
+        vec![0; (sectors * sectors + 7) / 8]
+    }
+
+    /// A blockmap with a single block holding every linedef
+    fn blockmap_lump(&self) -> Vec<u8> {
+        let x = self.vertexes.iter().map(|v| v.0).min().unwrap_or(0);
+        let y = self.vertexes.iter().map(|v| v.1).min().unwrap_or(0);
+
+        let mut buf = vec![];
+        put_i16(&mut buf, x);
+        put_i16(&mut buf, y);
+        put_i16(&mut buf, 1);
+        put_i16(&mut buf, 1);
+        put_u16(&mut buf, 5);
+        put_u16(&mut buf, 0);
+        for i in 0..self.linedefs.len() {
+            put_u16(&mut buf, i as u16);
+        }
+        put_u16(&mut buf, 0xffff);
+        buf
+    }
+
+    pub fn add_to(&self, wad: WadBuilder, map_name: &str) -> WadBuilder {
+        wad.marker(map_name)
+            .lump("THINGS", self.things_lump())
+            .lump("LINEDEFS", self.linedefs_lump())
+            .lump("SIDEDEFS", self.sidedefs_lump())
+            .lump("VERTEXES", self.vertexes_lump())
+            .lump("SEGS", self.segs_lump())
+            .lump("SSECTORS", self.subsectors_lump())
+            .lump("NODES", self.nodes_lump())
+            .lump("SECTORS", self.sectors_lump())
+            .lump("REJECT", self.reject_lump())
+            .lump("BLOCKMAP", self.blockmap_lump())
+    }
+}
+
+/// A square room, split down the middle by a single BSP node. Subsector 0 is
+/// the east half and subsector 1 the west half. The player starts in the
+/// west half, facing east.
+pub fn room() -> MapData {
+    let (s, h) = (ROOM_SIZE, ROOM_SIZE / 2);
+
+    MapData {
+        things: vec![(32, h, 0, 1)],
+        vertexes: vec![(0, 0), (s, 0), (s, s), (0, s), (h, 0), (h, s)],
+        // Clockwise, so the right side faces into the room
+        linedefs: vec![
+            (0, 3, 0x0001, Some(0), None),
+            (3, 2, 0x0001, Some(1), None),
+            (2, 1, 0x0001, Some(2), None),
+            (1, 0, 0x0001, Some(3), None),
+        ],
+        line_specials: vec![],
+        sidedefs: vec![("-", "-", "WALL", 0); 4],
+        segs: vec![
+            // East half
+            (5, 2, 1, false, h),
+            (2, 1, 2, false, 0),
+            (1, 4, 3, false, 0),
+            // West half
+            (4, 0, 3, false, h),
+            (0, 3, 0, false, 0),
+            (3, 5, 1, false, 0),
+        ],
+        subsectors: vec![(3, 0), (3, 3)],
+        nodes: vec![Node {
+            partition: (h, 0, 0, s),
+            right_bbox: (s, 0, h, s),
+            left_bbox: (s, 0, 0, h),
+            right_child: SUBSECTOR,
+            left_child: SUBSECTOR | 1,
+        }],
+        sectors: vec![(0, ROOM_HEIGHT, "FLOOR", "CEIL", 160)],
+        sector_tags: vec![],
+    }
+}
+
+/// The same room as `room()`, divided by a two-sided line between the two
+/// halves. The east half has the given floor and ceiling heights.
+pub fn step_room(floor: i16, ceil: i16) -> MapData {
+    MapData {
+        linedefs: vec![
+            (0, 3, 0x0001, Some(0), None),
+            (3, 5, 0x0001, Some(1), None),
+            (5, 2, 0x0001, Some(2), None),
+            (2, 1, 0x0001, Some(3), None),
+            (1, 4, 0x0001, Some(4), None),
+            (4, 0, 0x0001, Some(5), None),
+            (4, 5, 0x0004, Some(6), Some(7)),
+        ],
+        sidedefs: vec![
+            ("-", "-", "WALL", 0),
+            ("-", "-", "WALL", 0),
+            ("-", "-", "WALL", 1),
+            ("-", "-", "WALL", 1),
+            ("-", "-", "WALL", 1),
+            ("-", "-", "WALL", 0),
+            ("WALL", "WALL", "-", 1),
+            ("WALL", "WALL", "-", 0),
+        ],
+        segs: vec![
+            // East half
+            (5, 2, 2, false, 0),
+            (2, 1, 3, false, 0),
+            (1, 4, 4, false, 0),
+            (4, 5, 6, false, 0),
+            // West half
+            (4, 0, 5, false, 0),
+            (0, 3, 0, false, 0),
+            (3, 5, 1, false, 0),
+            (5, 4, 6, true, 0),
+        ],
+        subsectors: vec![(4, 0), (4, 4)],
+        sectors: vec![
+            (0, ROOM_HEIGHT, "FLOOR", "CEIL", 160),
+            (floor, ceil, "FLOOR", "CEIL", 160),
+        ],
+        ..room()
+    }
+}
+
+/// The graphics resources needed by the renderer: a palette, colormaps, one
+/// wall texture and two flats
+pub fn resources() -> WadBuilder {
+    WadBuilder::new()
+        .lump("PLAYPAL", playpal())
+        .lump("COLORMAP", colormap())
+        .lump("PNAMES", pnames(&["WALLPAT"]))
+        .lump("TEXTURE1", texture_directory(&[("WALL", 64, 128, 0)]))
+        .marker("S_START")
+        .marker("S_END")
+        .marker("P_START")
+        .lump("WALLPAT", patch(64, 128, WALL_COLOR))
+        .marker("P_END")
+        .marker("F_START")
+        .lump("FLOOR", flat(FLOOR_COLOR))
+        .lump("CEIL", flat(CEIL_COLOR))
+        .marker("F_END")
+}
+
+/// A WAD with the test resources and `room()` as E1M1
+pub fn room_wad() -> wad::Wad {
+    room().add_to(resources(), "E1M1").parse()
+}
@@ -0,0 +1,141 @@
+// Thing types that have a visible sprite in the idle state, with the sprite
+// name and frame they are spawned with. Player starts, teleport destinations
+// and other invisible markers are left out.
+const THING_SPRITES: &[(u16, &[u8; 4], u8)] = &[
+    // Monsters
+    (7, b"SPID", b'A'),
+    (9, b"SPOS", b'A'),
+    (16, b"CYBR", b'A'),
+    (58, b"SARG", b'A'),
+    (64, b"VILE", b'A'),
+    (65, b"CPOS", b'A'),
+    (66, b"SKEL", b'A'),
+    (67, b"FATT", b'A'),
+    (68, b"BSPI", b'A'),
+    (69, b"BOS2", b'A'),
+    (71, b"PAIN", b'A'),
+    (72, b"KEEN", b'A'),
+    (84, b"SSWV", b'A'),
+    (88, b"BBRN", b'A'),
+    (3001, b"TROO", b'A'),
+    (3002, b"SARG", b'A'),
+    (3003, b"BOSS", b'A'),
+    (3004, b"POSS", b'A'),
+    (3005, b"HEAD", b'A'),
+    (3006, b"SKUL", b'A'),
+    // Weapons
+    (82, b"SGN2", b'A'),
+    (2001, b"SHOT", b'A'),
+    (2002, b"MGUN", b'A'),
+    (2003, b"LAUN", b'A'),
+    (2004, b"PLAS", b'A'),
+    (2005, b"CSAW", b'A'),
+    (2006, b"BFUG", b'A'),
+    // Ammunition
+    (8, b"BPAK", b'A'),
+    (17, b"CELP", b'A'),
+    (2007, b"CLIP", b'A'),
+    (2008, b"SHEL", b'A'),
+    (2010, b"ROCK", b'A'),
+    (2046, b"BROK", b'A'),
+    (2047, b"CELL", b'A'),
+    (2048, b"AMMO", b'A'),
+    (2049, b"SBOX", b'A'),
+    // Health, armor and powerups
+    (83, b"MEGA", b'A'),
+    (2011, b"STIM", b'A'),
+    (2012, b"MEDI", b'A'),
+    (2013, b"SOUL", b'A'),
+    (2014, b"BON1", b'A'),
+    (2015, b"BON2", b'A'),
+    (2018, b"ARM1", b'A'),
+    (2019, b"ARM2", b'A'),
+    (2022, b"PINV", b'A'),
+    (2023, b"PSTR", b'A'),
+    (2024, b"PINS", b'A'),
+    (2025, b"SUIT", b'A'),
+    (2026, b"PMAP", b'A'),
+    (2045, b"PVIS", b'A'),
+    // Keys
+    (5, b"BKEY", b'A'),
+    (6, b"YKEY", b'A'),
+    (13, b"RKEY", b'A'),
+    (38, b"RSKU", b'A'),
+    (39, b"YSKU", b'A'),
+    (40, b"BSKU", b'A'),
+    // Obstacles and decorations
+    (10, b"PLAY", b'W'),
+    (12, b"PLAY", b'W'),
+    (15, b"PLAY", b'N'),
+    (18, b"POSS", b'L'),
+    (19, b"SPOS", b'L'),
+    (20, b"TROO", b'M'),
+    (21, b"SARG", b'N'),
+    (22, b"HEAD", b'L'),
+    (24, b"POL5", b'A'),
+    (25, b"POL1", b'A'),
+    (26, b"POL6", b'A'),
+    (27, b"POL4", b'A'),
+    (28, b"POL2", b'A'),
+    (29, b"POL3", b'A'),
+    (30, b"COL1", b'A'),
+    (31, b"COL2", b'A'),
+    (32, b"COL3", b'A'),
+    (33, b"COL4", b'A'),
+    (34, b"CAND", b'A'),
+    (35, b"CBRA", b'A'),
+    (36, b"COL5", b'A'),
+    (37, b"COL6", b'A'),
+    (41, b"CEYE", b'A'),
+    (42, b"FSKU", b'A'),
+    (43, b"TRE1", b'A'),
+    (44, b"TBLU", b'A'),
+    (45, b"TGRN", b'A'),
+    (46, b"TRED", b'A'),
+    (47, b"SMIT", b'A'),
+    (48, b"ELEC", b'A'),
+    (49, b"GOR1", b'A'),
+    (50, b"GOR2", b'A'),
+    (51, b"GOR3", b'A'),
+    (52, b"GOR4", b'A'),
+    (53, b"GOR5", b'A'),
+    (54, b"TRE2", b'A'),
+    (55, b"SMBT", b'A'),
+    (56, b"SMGT", b'A'),
+    (57, b"SMRT", b'A'),
+    (59, b"GOR2", b'A'),
+    (60, b"GOR4", b'A'),
+    (61, b"GOR3", b'A'),
+    (62, b"GOR5", b'A'),
+    (63, b"GOR1", b'A'),
+    (70, b"FCAN", b'A'),
+    (73, b"HDB1", b'A'),
+    (74, b"HDB2", b'A'),
+    (75, b"HDB3", b'A'),
+    (76, b"HDB4", b'A'),
+    (77, b"HDB5", b'A'),
+    (78, b"HDB6", b'A'),
+    (79, b"POB1", b'A'),
+    (80, b"POB2", b'A'),
+    (81, b"BRS1", b'A'),
+    (85, b"TLMP", b'A'),
+    (86, b"TLP2", b'A'),
+    (2028, b"COLU", b'A'),
+    (2035, b"BAR1", b'A'),
+];
+
+// Thing flag for things that only appear in multiplayer games
+const MULTIPLAYER_ONLY: u16 = 0x0010;
+
+/// Sprite name and frame shown for a map thing, if it is visible in a
+/// single player game
+pub fn thing_sprite(thing: &wad_map::Thing) -> Option<(&'static [u8; 4], u8)> {
+    if thing.flags & MULTIPLAYER_ONLY != 0 {
+        return None;
+    }
+
+    THING_SPRITES
+        .iter()
+        .find(|&&(thing_type, _, _)| thing_type == thing.thing_type)
+        .map(|&(_, sprite, frame)| (sprite, frame))
+}
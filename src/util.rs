@@ -9,6 +9,8 @@ use std::ops::Range;
 use wad_gfx::Sprite;
 use wad_map::*;
 
+use crate::error::{name_str, Error};
+
 pub fn add(r: Range<i32>, d: i32) -> Range<i32> {
     (r.start + d)..(r.end + d)
 }
@@ -49,6 +51,32 @@ pub fn put_sprite(trg: &mut ArrayViewMut2<u8>, pos_x: i16, pos_y: i16, sprite: &
     }
 }
 
+/// Draw a column of a sprite scaled by `scale`, with its top at row `top`,
+/// within the rows of `clip`. Each pixel is drawn through `colormap`.
+pub fn put_scaled_column(
+    trg: &mut ArrayViewMut2<u8>,
+    x: usize,
+    top: f32,
+    scale: f32,
+    sprite: &Sprite,
+    column: u32,
+    clip: Range<i32>,
+    colormap: impl Fn(u8) -> u8,
+) {
+    for span in sprite.col(column) {
+        let span_y_top = top + span.top as f32 * scale;
+        let span_y_bottom = span_y_top + span.pixels.len() as f32 * scale;
+
+        let y_range = span_y_top.round() as i32..span_y_bottom.round() as i32;
+
+        for y in intersect(y_range, clip.clone()) {
+            let s = ((y as f32 + 0.5 - span_y_top) / scale) as usize;
+            let s = min(s, span.pixels.len() - 1);
+            trg[[y as usize, x]] = colormap(span.pixels[s]);
+        }
+    }
+}
+
 pub fn point(trg: &mut ArrayViewMut2<u8>, p: Vector2<f32>, col: u8) {
     let p: Vector2<i32> = p.cast().unwrap();
     let on_screen = p.x >= 0 && p.x < 320 && p.y >= 0 && p.y < 200;
@@ -71,39 +99,173 @@ pub fn fill(trg: &mut ArrayViewMut2<u8>, col: u8) {
     }
 }
 
+/// The zero padded lump name for `name`, which may itself be zero padded or
+/// terminated. Lump names are upper case and at most 8 characters. Longer
+/// names are cut short rather than rejected, as vanilla does when looking
+/// lumps up.
+pub fn lump_name(name: &[u8]) -> [u8; 8] {
+    let mut id = [0u8; 8];
+    for (dst, src) in id.iter_mut().zip(name.iter().take_while(|&&x| x != 0)) {
+        *dst = src.to_ascii_uppercase();
+    }
+    id
+}
+
+pub fn required_lump<'a>(wad: &wad::WadSlice<'a>, name: &str) -> Result<&'a [u8], Error> {
+    wad.by_id(&lump_name(name.as_bytes()))
+        .ok_or_else(|| Error::MissingLump(name.to_owned()))
+}
+
+/// A required lump that must be at least `size` bytes long
+pub fn required_lump_of_size<'a>(
+    wad: &wad::WadSlice<'a>,
+    name: &str,
+    size: usize,
+) -> Result<&'a [u8], Error> {
+    let lump = required_lump(wad, name)?;
+    if lump.len() < size {
+        return Err(Error::InvalidWad(format!(
+            "{} is {} bytes, expected at least {}",
+            name,
+            lump.len(),
+            size
+        )));
+    }
+    Ok(lump)
+}
+
+// Whether the lump is a picture whose columns all lie within it, so that
+// drawing it cannot read past its end
+fn is_valid_patch(lump: &[u8]) -> bool {
+    let read_u16 = |offset: usize| {
+        lump.get(offset..offset + 2)
+            .map(|x| u16::from_le_bytes([x[0], x[1]]) as usize)
+    };
+    let read_u32 = |offset: usize| {
+        lump.get(offset..offset + 4)
+            .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]) as usize)
+    };
+
+    let width = match read_u16(0) {
+        Some(width) if width > 0 => width,
+        _ => return false,
+    };
+
+    (0..width).all(|x| {
+        let mut offset = match read_u32(8 + x * 4) {
+            Some(offset) => offset,
+            None => return false,
+        };
+
+        // Posts are a top row, a length, a padding byte, the pixels and
+        // another padding byte. A top row of 0xff ends the column.
+        loop {
+            match lump.get(offset) {
+                Some(0xff) => return true,
+                Some(_) => match lump.get(offset + 1) {
+                    Some(&length) => offset += length as usize + 4,
+                    None => return false,
+                },
+                None => return false,
+            }
+        }
+    })
+}
+
+/// A required lump in picture format, such as a weapon or status bar graphic
+pub fn required_patch<'a>(wad: &wad::WadSlice<'a>, name: &str) -> Result<Sprite<'a>, Error> {
+    let lump = required_lump(wad, name)?;
+    if !is_valid_patch(lump) {
+        return Err(Error::InvalidWad(format!(
+            "{} is not a valid picture",
+            name
+        )));
+    }
+    Ok(Sprite::new(lump))
+}
+
+fn required_index(wad: &wad::WadSlice, name: &str) -> Result<usize, Error> {
+    wad.index_of(&lump_name(name.as_bytes()))
+        .ok_or_else(|| Error::MissingLump(name.to_owned()))
+}
+
+/// A lump belonging to the given map, such as BLOCKMAP. Only the lumps
+/// following the map marker are searched, so a missing lump is not taken from
+/// the next map.
+pub fn map_lump<'a>(wad: &wad::WadSlice<'a>, map_name: &str, name: &str) -> Option<&'a [u8]> {
+    let marker = wad.index_of(&lump_name(map_name.as_bytes()))?;
+    let end = crate::merge::map_end_by(marker, |i, lump| {
+        wad.entry_id(i) == Some(wad::EntryId::from_bytes(&lump_name(lump)))
+    });
+    wad.slice(marker + 1..end)
+        .by_id(&lump_name(name.as_bytes()))
+}
+
+use crate::animation::{animdefs, Animations};
 use std::collections::HashMap;
 
 pub struct TextureProvider<'a> {
     wad: wad::WadSlice<'a>,
     patch_provider: wad_gfx::EagerPatchProvider<'a>,
-    texture_dir: wad_gfx::TextureDirectory<'a>,
+    texture_dirs: Vec<wad_gfx::TextureDirectory<'a>>,
     cache: HashMap<wad::EntryId, Vec<u8>>,
+    animations: Animations,
+    tic: u32,
 }
 
 impl<'a> TextureProvider<'a> {
-    pub fn new(wad: wad::WadSlice) -> TextureProvider {
-        let pnames = wad
-            .by_id(b"PNAMES")
-            .unwrap()
+    pub fn new(wad: wad::WadSlice) -> Result<TextureProvider, Error> {
+        let pnames = required_lump(&wad, "PNAMES")?
             .iter()
             .map(|x| x.to_ascii_uppercase())
             .collect::<Vec<_>>();
         let pnames = wad_gfx::parse_pnames(&pnames);
-        let texture_dir = wad.by_id(b"TEXTURE1").unwrap();
 
-        TextureProvider {
+        // Doom II and registered Doom keep additional textures in TEXTURE2
+        let texture1 = required_lump(&wad, "TEXTURE1")?;
+        let texture2 = wad.by_id(&lump_name(b"TEXTURE2"));
+        let texture_dirs = std::iter::once(texture1)
+            .chain(texture2)
+            .map(wad_gfx::TextureDirectory::new)
+            .collect::<Vec<_>>();
+
+        // Animations run through textures in the order of the directories
+        let names = texture_dirs
+            .iter()
+            .flat_map(|dir| (0..dir.len()).map(move |i| dir.texture(i).name()))
+            .map(|name| wad::EntryId::from_bytes(&name))
+            .collect::<Vec<_>>();
+        let animations = Animations::new(
+            animdefs(&wad).iter().filter(|def| def.is_texture),
+            |name| {
+                let id = wad::EntryId::from_bytes(name);
+                names.iter().position(|&x| x == id)
+            },
+            |i| names.get(i).cloned(),
+        );
+
+        Ok(TextureProvider {
             wad: wad.slice(..),
             patch_provider: wad_gfx::EagerPatchProvider::new(wad, pnames),
-            texture_dir: wad_gfx::TextureDirectory::new(texture_dir),
+            texture_dirs,
             cache: HashMap::new(),
-        }
+            animations,
+            tic: 0,
+        })
+    }
+
+    /// Show animated textures as they are at the given tic
+    pub fn set_tic(&mut self, tic: u32) {
+        self.tic = tic;
     }
 
     fn find_texture(&self, id: wad::EntryId) -> Option<wad_gfx::Texture<'a>> {
-        for i in 0..self.texture_dir.len() {
-            let t = self.texture_dir.texture(i);
-            if wad::EntryId::from_bytes(&t.name()) == id {
-                return Some(t);
+        for texture_dir in &self.texture_dirs {
+            for i in 0..texture_dir.len() {
+                let t = texture_dir.texture(i);
+                if wad::EntryId::from_bytes(&t.name()) == id {
+                    return Some(t);
+                }
             }
         }
 
@@ -111,12 +273,15 @@ impl<'a> TextureProvider<'a> {
     }
 
     pub fn get_texture(&self, id: impl Into<wad::EntryId>) -> Option<Sprite> {
-        self.cache.get(&id.into()).map(|x| Sprite::new(x))
+        let id = self.animations.frame(id.into(), self.tic);
+        self.cache.get(&id).map(|x| Sprite::new(x))
     }
 
-    pub fn load_texture(&mut self, id: impl Into<wad::EntryId>) -> Result<(), ()> {
-        let id = id.into();
-        let texture = self.find_texture(id).ok_or(())?;
+    pub fn load_texture(&mut self, name: &[u8; 8]) -> Result<(), Error> {
+        let id = self.animations.frame(name.into(), self.tic);
+        let texture = self
+            .find_texture(id)
+            .ok_or_else(|| Error::MissingTexture(name_str(name)))?;
         let patch_provider = &self.patch_provider;
 
         self.cache
@@ -127,6 +292,118 @@ impl<'a> TextureProvider<'a> {
     }
 }
 
+pub struct FlatProvider<'a> {
+    flats: wad::WadSlice<'a>,
+    animations: Animations,
+    tic: u32,
+}
+
+impl<'a> FlatProvider<'a> {
+    pub fn new(wad: wad::WadSlice<'a>) -> Result<FlatProvider<'a>, Error> {
+        let start = required_index(&wad, "F_START")?;
+        let end = required_index(&wad, "F_END")?;
+        let flats = wad.slice(start + 1..end);
+
+        // Animations run through the flats in directory order
+        let animations = Animations::new(
+            animdefs(&wad).iter().filter(|def| !def.is_texture),
+            |name| flats.index_of(name),
+            |i| flats.entry_id(i),
+        );
+
+        Ok(FlatProvider {
+            flats,
+            animations,
+            tic: 0,
+        })
+    }
+
+    /// Show animated flats as they are at the given tic
+    pub fn set_tic(&mut self, tic: u32) {
+        self.tic = tic;
+    }
+
+    pub fn get_flat(&self, id: &[u8; 8]) -> Option<&'a [u8]> {
+        self.flats
+            .by_id(self.animations.frame(id.into(), self.tic))
+            .filter(|flat| flat.len() >= FLAT_SIZE * FLAT_SIZE)
+    }
+}
+
+pub const FLAT_SIZE: usize = 64;
+
+pub struct SpriteProvider<'a> {
+    sprites: wad::WadSlice<'a>,
+}
+
+impl<'a> SpriteProvider<'a> {
+    pub fn new(wad: wad::WadSlice<'a>) -> Result<SpriteProvider<'a>, Error> {
+        let start = required_index(&wad, "S_START")?;
+        let end = required_index(&wad, "S_END")?;
+
+        Ok(SpriteProvider {
+            sprites: wad.slice(start + 1..end),
+        })
+    }
+
+    fn lump(&self, name: &[u8]) -> Option<Sprite<'a>> {
+        self.sprites.by_id(&lump_name(name)).map(Sprite::new)
+    }
+
+    /// Look up the sprite for the given frame as seen from the given
+    /// rotation (1-8). Also returns whether the sprite must be drawn
+    /// mirrored, as rotations 2-4 and 6-8 may share a lump.
+    pub fn get_frame(
+        &self,
+        prefix: &[u8; 4],
+        frame: u8,
+        rotation: u8,
+    ) -> Option<(Sprite<'a>, bool)> {
+        let rot = b'0' + rotation;
+        let mirror = b'0' + (10 - rotation);
+
+        let name = |suffix: &[u8]| {
+            let mut name = prefix.to_vec();
+            name.extend_from_slice(suffix);
+            name
+        };
+
+        self.lump(&name(&[frame, rot]))
+            .map(|sprite| (sprite, false))
+            .or_else(|| {
+                self.lump(&name(&[frame, rot, frame, mirror]))
+                    .map(|sprite| (sprite, false))
+            })
+            .or_else(|| {
+                self.lump(&name(&[frame, mirror, frame, rot]))
+                    .map(|sprite| (sprite, true))
+            })
+            .or_else(|| {
+                self.lump(&name(&[frame, b'0']))
+                    .map(|sprite| (sprite, false))
+            })
+    }
+}
+
+/// Names of the maps in the WAD, in directory order. Both the ExMy naming of
+/// Doom and the MAPxx naming of Doom II are recognized.
+pub fn map_names(wad: &wad::WadSlice) -> Vec<String> {
+    let episode_maps = (1..=9).flat_map(|e| (1..=9).map(move |m| format!("E{}M{}", e, m)));
+    let mission_maps = (1..=99).map(|m| format!("MAP{:02}", m));
+
+    let mut maps = episode_maps
+        .chain(mission_maps)
+        .filter_map(|name| {
+            wad.index_of(&lump_name(name.as_bytes()))
+                .map(|index| (index, name))
+        })
+        .collect::<Vec<_>>();
+
+    maps.sort();
+
+    maps.into_iter().map(|(_, name)| name).collect()
+}
+
 pub fn generate_svg(mut out: impl std::fmt::Write, map: &wad_map::Map) -> std::fmt::Result {
     let mut bbox = BoundingBox::from(&map.vertexes);
     bbox.grow(20);
@@ -188,18 +465,112 @@ pub fn generate_svg(mut out: impl std::fmt::Write, map: &wad_map::Map) -> std::f
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::test_support::*;
 
     #[test]
     fn texture_provider() {
-        let wad = wad::parse_wad(Vec::from(include_bytes!("../doom1.wad") as &[u8])).unwrap();
-        let _ = TextureProvider::new(wad.as_slice());
+        let wad = room_wad();
+        let mut texture_provider = TextureProvider::new(wad.as_slice()).unwrap();
+
+        texture_provider.load_texture(b"WALL\0\0\0\0").unwrap();
+        let texture = texture_provider.get_texture(b"WALL\0\0\0\0").unwrap();
+        assert_eq!((texture.width(), texture.height()), (64, 128));
+
+        assert!(texture_provider.load_texture(b"NOWALL\0\0").is_err());
+    }
+
+    #[test]
+    fn animated_flats() {
+        let wad = WadBuilder::new()
+            .marker("F_START")
+            .lump("FLOOR", flat(FLOOR_COLOR))
+            .lump("NUKAGE1", flat(1))
+            .lump("NUKAGE2", flat(2))
+            .lump("NUKAGE3", flat(3))
+            .marker("F_END")
+            .parse();
+        let mut flat_provider = FlatProvider::new(wad.as_slice()).unwrap();
+
+        let color = |flat_provider: &FlatProvider, name| flat_provider.get_flat(name).unwrap()[0];
+
+        // The cycle starts at the second flat, as vanilla offsets it by the
+        // index of its first frame
+        assert_eq!(color(&flat_provider, b"NUKAGE1\0"), 2);
+        flat_provider.set_tic(8);
+        assert_eq!(color(&flat_provider, b"NUKAGE1\0"), 3);
+        assert_eq!(color(&flat_provider, b"NUKAGE3\0"), 2);
+        flat_provider.set_tic(16);
+        assert_eq!(color(&flat_provider, b"NUKAGE1\0"), 1);
+        assert_eq!(color(&flat_provider, b"FLOOR\0\0\0"), FLOOR_COLOR);
     }
 
     #[test]
     fn svg() {
-        let wad = wad::parse_wad(Vec::from(include_bytes!("../doom1.wad") as &[u8])).unwrap();
+        let wad = room_wad();
         let map = wad_map::read_map(&wad.as_slice(), "E1M1").unwrap();
         let mut buf = String::new();
-        let _ = generate_svg(&mut buf, &map);
+        generate_svg(&mut buf, &map).unwrap();
+
+        assert_eq!(buf.matches("<line x1=").count(), 4);
+        assert!(buf.contains(r#"<line x1="0" y1="0" x2="0" y2="256" />"#));
+    }
+
+    #[test]
+    fn map_names() {
+        let wad = room()
+            .add_to(room().add_to(resources(), "MAP02"), "E1M1")
+            .parse();
+        assert_eq!(super::map_names(&wad.as_slice()), ["MAP02", "E1M1"]);
+    }
+
+    #[test]
+    fn lump_sizes() {
+        let wad = WadBuilder::new()
+            .lump("PLAYPAL", vec![0; 10])
+            .lump("COLORMAP", colormap())
+            .parse();
+        let wad = wad.as_slice();
+
+        match required_lump_of_size(&wad, "PLAYPAL", 768) {
+            Err(Error::InvalidWad(_)) => (),
+            _ => panic!("Expected InvalidWad"),
+        }
+        assert!(required_lump_of_size(&wad, "COLORMAP", 34 * 256).is_ok());
+    }
+
+    #[test]
+    fn map_lumps() {
+        let wad = room()
+            .add_to(room().add_to(resources(), "E1M1"), "E1M2")
+            .lump("EXTRA", vec![1])
+            .parse();
+        let wad = wad.as_slice();
+
+        assert!(map_lump(&wad, "E1M1", "BLOCKMAP").is_some());
+        assert!(map_lump(&wad, "E1M1", "PLAYPAL").is_none());
+        assert!(map_lump(&wad, "E1M2", "EXTRA").is_none());
+        assert!(map_lump(&wad, "E1M3", "BLOCKMAP").is_none());
+    }
+
+    #[test]
+    fn patches() {
+        let mut truncated = patch(4, 8, 1);
+        truncated.truncate(truncated.len() - 1);
+
+        let wad = WadBuilder::new()
+            .lump("GOOD", patch(4, 8, 1))
+            .lump("EMPTY", vec![])
+            .lump("PLAYPAL", playpal())
+            .lump("TRUNC", truncated)
+            .parse();
+        let wad = wad.as_slice();
+
+        assert!(required_patch(&wad, "GOOD").is_ok());
+        for &name in &["EMPTY", "PLAYPAL", "TRUNC"] {
+            match required_patch(&wad, name) {
+                Err(Error::InvalidWad(_)) => (),
+                _ => panic!("Expected InvalidWad for {}", name),
+            }
+        }
     }
 }
@@ -0,0 +1,226 @@
+//! Golden-image tests for the renderer.
+//!
+//! Each pose is rendered through `State::render` and the 8-bit framebuffer is
+//! compared to a reference in `tests/golden/`. References are stored as binary
+//! PGM images of palette indices, so they can be viewed with most image
+//! viewers. On a mismatch the actual frame and a diff mask are written to
+//! `target/golden/` and the test fails with a report of every failing pose.
+//!
+//! Run with `BLESS=1` to write the current output as the new references.
+//!
+//! `golden_images` renders the synthetic maps from `test_support`, so it runs
+//! anywhere. `doom1_golden_images` needs `doom1.wad` in the crate root and is
+//! ignored by default; run it with `cargo test -- --ignored`.
+
+use cgmath::{Matrix2, Vector2};
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use wad_render::{renderer, Input, DEFAULT_FOV, SCREEN_HEIGHT, SCREEN_WIDTH, VANILLA_PIXEL_ASPECT};
+
+#[allow(dead_code)]
+#[path = "../src/test_support.rs"]
+mod test_support;
+
+const TAU: f32 = 2. * ::std::f32::consts::PI;
+
+const DOOM1_MAPS: &[&str] = &[
+    "E1M1", "E1M2", "E1M3", "E1M4", "E1M5", "E1M6", "E1M7", "E1M8", "E1M9",
+];
+
+// The room and a room with a raised step, from `test_support`
+const SYNTHETIC_MAPS: &[&str] = &["E1M1", "E1M2"];
+
+// Poses relative to the player 1 start: distance moved forward and the turn
+// in fractions of a revolution
+const POSES: &[(f32, f32)] = &[(0., 0.), (0., 0.25), (0., 0.5), (0., 0.75), (64., 0.)];
+
+type Frame = Vec<u8>;
+
+fn crate_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+}
+
+fn reference_path(name: &str) -> PathBuf {
+    crate_dir()
+        .join("tests/golden")
+        .join(format!("{}.pgm", name))
+}
+
+fn output_path(name: &str, kind: &str) -> PathBuf {
+    crate_dir()
+        .join("target/golden")
+        .join(format!("{}.{}.pgm", name, kind))
+}
+
+fn write_pgm(path: &Path, frame: &[u8]) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+
+    let mut buf = format!("P5\n{} {}\n255\n", SCREEN_WIDTH, SCREEN_HEIGHT).into_bytes();
+    buf.extend_from_slice(frame);
+    fs::write(path, buf).unwrap();
+}
+
+fn read_pgm(path: &Path) -> Option<Frame> {
+    let buf = fs::read(path).ok()?;
+    let header = format!("P5\n{} {}\n255\n", SCREEN_WIDTH, SCREEN_HEIGHT);
+
+    if !buf.starts_with(header.as_bytes()) {
+        panic!(
+            "{} is not a {}x{} PGM image",
+            path.display(),
+            SCREEN_WIDTH,
+            SCREEN_HEIGHT
+        );
+    }
+
+    Some(buf[header.len()..].to_vec())
+}
+
+fn render(state: &mut renderer::State, pos: Vector2<f32>, dir: Vector2<f32>) -> Frame {
+    let mut pal = [0; 768];
+    let mut buf = [0; SCREEN_WIDTH * SCREEN_HEIGHT];
+
+    state
+        .render(Input {
+            pal: &mut pal,
+            buf: &mut buf,
+            width: SCREEN_WIDTH,
+            height: SCREEN_HEIGHT,
+            pixel_aspect: VANILLA_PIXEL_ASPECT,
+            pos,
+            dir,
+            view_z: None,
+            pitch: 0.,
+            fov: DEFAULT_FOV,
+            palette: 0,
+            tic: 0,
+            weapon: None,
+            speed: 0.,
+            status_bar: None,
+        })
+        .unwrap();
+
+    buf.to_vec()
+}
+
+/// Describe how `actual` differs from `expected`, or `None` if they are equal
+fn compare(expected: &[u8], actual: &[u8]) -> Option<String> {
+    let diffs = (0..SCREEN_HEIGHT)
+        .flat_map(|y| (0..SCREEN_WIDTH).map(move |x| (x, y)))
+        .filter(|&(x, y)| expected[y * SCREEN_WIDTH + x] != actual[y * SCREEN_WIDTH + x])
+        .collect::<Vec<_>>();
+
+    if diffs.is_empty() {
+        return None;
+    }
+
+    let left = diffs.iter().map(|&(x, _)| x).min().unwrap();
+    let right = diffs.iter().map(|&(x, _)| x).max().unwrap();
+    let top = diffs.iter().map(|&(_, y)| y).min().unwrap();
+    let bottom = diffs.iter().map(|&(_, y)| y).max().unwrap();
+
+    Some(format!(
+        "{} pixels differ, within x {}..={}, y {}..={}",
+        diffs.len(),
+        left,
+        right,
+        top,
+        bottom
+    ))
+}
+
+fn diff_mask(expected: &[u8], actual: &[u8]) -> Frame {
+    expected
+        .iter()
+        .zip(actual)
+        .map(|(a, b)| if a == b { 0 } else { 255 })
+        .collect()
+}
+
+/// Render every pose of every map and compare them to the references named
+/// after `prefix`, panicking with a report of the ones that differ
+fn check_golden_images(wad: &wad::Wad, prefix: &str, maps: &[&str]) {
+    let bless = env::var_os("BLESS").is_some();
+    let mut failures = String::new();
+
+    for &map in maps {
+        let mut state = renderer::State::new(wad, map).unwrap();
+        let (spawn_pos, spawn_dir) = state.spawn_point().unwrap();
+
+        for (i, &(forward, turn)) in POSES.iter().enumerate() {
+            let name = format!("{}-{}-{}", prefix, map, i);
+
+            let (s, c) = (turn * TAU).sin_cos();
+            let dir = Matrix2::new(c, s, -s, c) * spawn_dir;
+            let pos = spawn_pos + forward * spawn_dir;
+            let actual = render(&mut state, pos, dir);
+
+            if bless {
+                write_pgm(&reference_path(&name), &actual);
+                continue;
+            }
+
+            let expected = match read_pgm(&reference_path(&name)) {
+                Some(expected) => expected,
+                None => {
+                    writeln!(failures, "{}: missing reference image", name).unwrap();
+                    write_pgm(&output_path(&name, "actual"), &actual);
+                    continue;
+                }
+            };
+
+            if let Some(report) = compare(&expected, &actual) {
+                writeln!(failures, "{}: {}", name, report).unwrap();
+                write_pgm(&output_path(&name, "actual"), &actual);
+                write_pgm(&output_path(&name, "diff"), &diff_mask(&expected, &actual));
+            }
+        }
+    }
+
+    if !failures.is_empty() {
+        panic!(
+            "Rendering differs from the reference images. Actual frames and \
+             diffs are in target/golden/. Run with BLESS=1 to accept the \
+             changes.\n{}",
+            failures
+        );
+    }
+}
+
+#[test]
+fn golden_images() {
+    let wad = test_support::step_room(24, 96).add_to(
+        test_support::room().add_to(test_support::resources(), "E1M1"),
+        "E1M2",
+    );
+
+    check_golden_images(&wad.parse(), "synthetic", SYNTHETIC_MAPS);
+}
+
+#[test]
+#[ignore]
+fn doom1_golden_images() {
+    let buf = fs::read(crate_dir().join("doom1.wad"))
+        .expect("doom1.wad must be in the crate root for this test");
+    let wad = wad::parse_wad(buf).unwrap();
+
+    check_golden_images(&wad, "doom1", DOOM1_MAPS);
+}
+
+#[test]
+fn compare_reports_differences() {
+    let expected = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT];
+    let mut actual = expected.clone();
+
+    assert_eq!(compare(&expected, &actual), None);
+
+    actual[10 * SCREEN_WIDTH + 20] = 1;
+    actual[30 * SCREEN_WIDTH + 5] = 1;
+
+    assert_eq!(
+        compare(&expected, &actual).unwrap(),
+        "2 pixels differ, within x 5..=20, y 10..=30"
+    );
+}